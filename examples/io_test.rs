@@ -162,13 +162,14 @@ fn qd_n(
                         ctr -= 1;
                         ios += 1;
                     }
-                    qpair.submit_io(
+                    let submission = qpair.submit_io(
                         ns_id,
                         block_size,
                         &buffer.slice((ctr * bytes)..(ctr + 1) * bytes),
                         lba * blocks,
                         write,
                     );
+                    assert!(submission.is_complete(), "queue full");
                     total += before.elapsed();
                     ctr += 1;
                 }
@@ -196,13 +197,14 @@ fn qd_n(
                         qpair.complete_io(1);
                         ctr -= 1;
                     }
-                    qpair.submit_io(
+                    let submission = qpair.submit_io(
                         ns_id,
                         block_size,
                         &buffer.slice((ctr * bytes)..(ctr + 1) * bytes),
                         lba * blocks,
                         write,
                     );
+                    assert!(submission.is_complete(), "queue full");
                     total += before.elapsed();
                     ctr += 1;
                 }
@@ -381,20 +383,22 @@ fn qd_n_zns_write(
                         ios += 1;
                     }
                     if append {
-                        qpair.append_io(
+                        let submission = qpair.append_io(
                             ns_id,
                             block_size, 
                             &buffer.slice((ctr * bytes)..(ctr + 1) * bytes), 
                             zone * zone_size
                         );
+                        assert!(submission.is_complete(), "queue full");
                     } else {
-                        qpair.submit_io(
+                        let submission = qpair.submit_io(
                             ns_id,
                             block_size,
                             &buffer.slice((ctr * bytes)..(ctr + 1) * bytes),
                             lba,
                             true,
                         );
+                        assert!(submission.is_complete(), "queue full");
                     }
                     total += before.elapsed();
                     ctr += 1;
@@ -425,19 +429,21 @@ fn qd_n_zns_write(
                         ctr -= 1;
                     }
                     if !append {
-                        qpair.submit_io(
+                        let submission = qpair.submit_io(
                             ns_id,
                             block_size,
                             &buffer.slice((ctr * bytes)..(ctr + 1) * bytes),
                             lba,
                             true,
                         );
+                        assert!(submission.is_complete(), "queue full");
                     } else {
-                        qpair.append_io(
+                        let submission = qpair.append_io(
                             ns_id,
                             block_size, 
                             &buffer.slice((ctr * bytes)..(ctr + 1) * bytes), 
                             lba);
+                        assert!(submission.is_complete(), "queue full");
                     }
                     total += before.elapsed();
                     ctr += 1;
@@ -158,13 +158,14 @@ fn qd_n(
                         qpair.complete_io(1);
                         ctr -= 1;
                     }
-                    qpair.submit_io(
+                    let submission = qpair.submit_io(
                         ns_id,
                         block_size,
                         &buffer.slice((ctr * bytes)..(ctr + 1) * bytes),
                         lba * blocks,
                         write,
                     );
+                    assert!(submission.is_complete(), "queue full");
                     total += before.elapsed();
                     ctr += 1;
                 }
@@ -315,13 +316,14 @@ fn qd_n_alt(
                         qpair.complete_io(1);
                         ctr -= 1;
                     }
-                    qpair.submit_io(
+                    let submission = qpair.submit_io(
                         ns_id,
                         block_size,
                         &buffer.slice((ctr * bytes)..(ctr + 1) * bytes),
                         lba * blocks,
                         write,
                     );
+                    assert!(submission.is_complete(), "queue full");
                     total += before.elapsed();
                     ctr += 1;
                 }
@@ -0,0 +1,64 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::{env, process};
+use vroom::replay::{self, QueuePairTarget, ReplayConfig, ReplayTiming};
+use vroom::trace::TraceReader;
+use vroom::QUEUE_LENGTH;
+
+const USAGE: &str =
+    "Usage: cargo run --example replay <pci bus id> <trace|fio|blkparse> <file> [--ns <id>] [--qd <depth>] [--fast]";
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args();
+    args.next();
+
+    let (pci_addr, format, path) = match (args.next(), args.next(), args.next()) {
+        (Some(pci_addr), Some(format), Some(path)) => (pci_addr, format, path),
+        _ => {
+            eprintln!("{USAGE}");
+            process::exit(1);
+        }
+    };
+
+    let mut ns_id = 1;
+    let mut config = ReplayConfig::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ns" => ns_id = args.next().and_then(|id| id.parse().ok()).expect(USAGE),
+            "--qd" => config.queue_depth = args.next().and_then(|qd| qd.parse().ok()).expect(USAGE),
+            "--fast" => config.timing = ReplayTiming::AsFastAsPossible,
+            _ => {
+                eprintln!("{USAGE}");
+                process::exit(1);
+            }
+        }
+    }
+
+    let mut nvme = vroom::init(&pci_addr)?;
+    let block_size = nvme.namespaces.get(&ns_id).ok_or("namespace not found")?.block_size;
+
+    let ops = match format.as_str() {
+        "trace" => {
+            let reader = TraceReader::new(BufReader::new(File::open(path)?))?;
+            replay::ops_from_trace(reader.collect::<Result<Vec<_>, _>>()?)
+        }
+        "fio" => replay::parse_fio_iolog(&fs::read_to_string(path)?, ns_id, block_size)?,
+        "blkparse" => replay::parse_blkparse(&fs::read_to_string(path)?, ns_id, block_size)?,
+        _ => {
+            eprintln!("{USAGE}");
+            process::exit(1);
+        }
+    };
+    println!("replaying {} ops", ops.len());
+
+    let mut qpair = nvme.create_io_queue_pair(QUEUE_LENGTH)?;
+    let report = {
+        let mut target = QueuePairTarget::new(&mut qpair, block_size, config.queue_depth)?;
+        replay::replay(&mut target, &ops, &config)
+    };
+    println!("{report}");
+
+    nvme.delete_io_queue_pair(qpair)?;
+    Ok(())
+}
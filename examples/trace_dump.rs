@@ -0,0 +1,25 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::{env, process};
+use vroom::trace::TraceReader;
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args();
+    args.next();
+
+    let path = match args.next() {
+        Some(arg) => arg,
+        None => {
+            eprintln!("Usage: cargo run --example trace_dump <trace file>");
+            process::exit(1);
+        }
+    };
+
+    let reader = TraceReader::new(BufReader::new(File::open(path)?))?;
+    for record in reader {
+        println!("{}", record?);
+    }
+
+    Ok(())
+}
@@ -32,7 +32,7 @@ pub struct NvmeCommand {
 }
 
 impl NvmeCommand {
-    pub fn create_io_completion_queue(c_id: u16, qid: u16, ptr: usize, size: u16) -> Self {
+    pub fn create_io_completion_queue(c_id: u16, qid: u16, ptr: usize, size: u16, contiguous: bool) -> Self {
         Self {
             opcode: 5,
             flags: 0,
@@ -42,7 +42,7 @@ impl NvmeCommand {
             md_ptr: 0,
             d_ptr: [ptr as u64, 0],
             cdw10: ((size as u32) << 16) | (qid as u32),
-            cdw11: 1, // Physically Contiguous
+            cdw11: contiguous as u32, // Physically Contiguous, otherwise ptr points to a PRP list
             cdw12: 0,
             cdw13: 0,
             cdw14: 0,
@@ -56,6 +56,8 @@ impl NvmeCommand {
         ptr: usize,
         size: u16,
         cq_id: u16,
+        qprio: u8,
+        contiguous: bool,
     ) -> Self {
         Self {
             opcode: 1,
@@ -66,8 +68,8 @@ impl NvmeCommand {
             md_ptr: 0,
             d_ptr: [ptr as u64, 0],
             cdw10: ((size as u32) << 16) | (q_id as u32),
-            cdw11: ((cq_id as u32) << 16) | 1, /* Physically Contiguous */
-            //TODO: QPRIO
+            // Queue priority, only used with weighted round robin arbitration
+            cdw11: ((cq_id as u32) << 16) | ((qprio as u32 & 0x3) << 1) | contiguous as u32, /* Physically Contiguous */
             cdw12: 0, //TODO: NVMSETID
             cdw13: 0,
             cdw14: 0,
@@ -183,11 +185,14 @@ impl NvmeCommand {
         }
     }
 
-    pub fn get_features(c_id: u16, ptr: usize, fid: u8) -> Self {
+    // sel: 0 = current, 1 = default, 2 = saved, 3 = supported capabilities
+    pub fn get_features(c_id: u16, ptr: usize, fid: u8, sel: u8, cdw11: u32) -> Self {
         Self {
             opcode: 0xA,
+            c_id,
             d_ptr: [ptr as u64, 0],
-            cdw10: u32::from(fid), // TODO: SEL
+            cdw10: ((sel as u32 & 0x7) << 8) | u32::from(fid),
+            cdw11,
             ..Default::default()
         }
     }
@@ -228,7 +233,56 @@ impl NvmeCommand {
         }
     }
 
-    pub(crate) fn format_nvm(c_id: u16, ns_id: u32) -> Self {
+    pub fn io_flush(c_id: u16, ns_id: u32) -> Self {
+        Self {
+            opcode: 0,
+            flags: 0,
+            c_id,
+            ns_id,
+            _rsvd: 0,
+            md_ptr: 0,
+            d_ptr: [0, 0],
+            cdw10: 0,
+            cdw11: 0,
+            cdw12: 0,
+            cdw13: 0,
+            cdw14: 0,
+            cdw15: 0,
+        }
+    }
+
+    pub fn io_compare(c_id: u16, ns_id: u32, lba: u64, blocks_1: u16, ptr0: u64, ptr1: u64) -> Self {
+        Self {
+            opcode: 5,
+            flags: 0,
+            c_id,
+            ns_id,
+            _rsvd: 0,
+            md_ptr: 0,
+            d_ptr: [ptr0, ptr1],
+            cdw10: lba as u32,
+            cdw11: (lba >> 32) as u32,
+            cdw12: blocks_1 as u32,
+            cdw13: 0,
+            cdw14: 0,
+            cdw15: 0,
+        }
+    }
+
+    /// Marks the command as the first (FUSE = 01b) or second (FUSE = 10b) half of a fused operation
+    pub fn fused(mut self, second: bool) -> Self {
+        self.flags = (self.flags & !0b11) | if second { 0b10 } else { 0b01 };
+        self
+    }
+
+    /// Sets the directive type and directive specific value of a write, e.g. DTYPE = 1 and the stream id
+    pub fn directive(mut self, dtype: u8, dspec: u16) -> Self {
+        self.cdw12 = (self.cdw12 & !(0xF << 20)) | ((dtype as u32 & 0xF) << 20);
+        self.cdw13 = (self.cdw13 & 0xFFFF) | ((dspec as u32) << 16);
+        self
+    }
+
+    pub(crate) fn format_nvm(c_id: u16, ns_id: u32, lbaf: u8, mset: bool, pi: u8, pil: bool, ses: u8) -> Self {
         Self {
             opcode: 0x80,
             flags: 0,
@@ -237,8 +291,11 @@ impl NvmeCommand {
             _rsvd: 0,
             md_ptr: 0,
             d_ptr: [0, 0],
-            cdw10: 1 << 9,
-            // TODO: dealloc and prinfo bits
+            cdw10: ((ses as u32 & 0x7) << 9)
+                | ((pil as u32) << 8)
+                | ((pi as u32 & 0x7) << 5)
+                | ((mset as u32) << 4)
+                | (lbaf as u32 & 0xF),
             cdw11: 0,
             cdw12: 0,
             cdw13: 0,
@@ -247,6 +304,92 @@ impl NvmeCommand {
         }
     }
 
+    // sel: 0 = create, 1 = delete; csi is only used when creating
+    pub fn namespace_management(c_id: u16, ptr: usize, ns_id: u32, sel: u8, csi: u8) -> Self {
+        Self {
+            opcode: 0xD,
+            c_id,
+            ns_id,
+            d_ptr: [ptr as u64, 0],
+            cdw10: sel as u32,
+            cdw11: (csi as u32) << 24,
+            ..Default::default()
+        }
+    }
+
+    // sel: 0 = attach, 1 = detach, ptr points to a controller list
+    pub fn namespace_attachment(c_id: u16, ptr: usize, ns_id: u32, sel: u8) -> Self {
+        Self {
+            opcode: 0x15,
+            c_id,
+            ns_id,
+            d_ptr: [ptr as u64, 0],
+            cdw10: sel as u32,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn sanitize(c_id: u16, sanact: u8, ause: bool, owpass: u8, oipbp: bool, ndas: bool, ovrpat: u32) -> Self {
+        Self {
+            opcode: 0x84,
+            c_id,
+            cdw10: ((ndas as u32) << 9)
+                | ((oipbp as u32) << 8)
+                | ((owpass as u32 & 0xF) << 4)
+                | ((ause as u32) << 3)
+                | (sanact as u32 & 0x7),
+            cdw11: ovrpat,
+            ..Default::default()
+        }
+    }
+
+    // numd is the 0's based number of dwords, ofst the offset in dwords
+    pub(crate) fn firmware_image_download(c_id: u16, numd: u32, ofst: u32, ptr0: u64, ptr1: u64) -> Self {
+        Self {
+            opcode: 0x11,
+            c_id,
+            d_ptr: [ptr0, ptr1],
+            cdw10: numd,
+            cdw11: ofst,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn firmware_commit(c_id: u16, fs: u8, ca: u8) -> Self {
+        Self {
+            opcode: 0x10,
+            c_id,
+            cdw10: ((ca as u32 & 0x7) << 3) | (fs as u32 & 0x7),
+            ..Default::default()
+        }
+    }
+
+    pub fn set_features(c_id: u16, ptr: usize, fid: u8, save: bool, cdw11: u32) -> Self {
+        Self {
+            opcode: 0x9,
+            c_id,
+            d_ptr: [ptr as u64, 0],
+            cdw10: ((save as u32) << 31) | u32::from(fid),
+            cdw11,
+            ..Default::default()
+        }
+    }
+
+    // Set Features Host Memory Buffer, hsize is in memory pages and hmdl points to hmdlec descriptor entries
+    pub(crate) fn set_host_memory_buffer(c_id: u16, ehm: bool, mr: bool, hsize: u32, hmdl: u64, hmdlec: u32) -> Self {
+        Self {
+            opcode: 0x9,
+            c_id,
+            cdw10: 0x0D,
+            cdw11: ((mr as u32) << 1) | ehm as u32,
+            cdw12: hsize,
+            cdw13: hmdl as u32,
+            cdw14: (hmdl >> 32) as u32,
+            cdw15: hmdlec,
+            ..Default::default()
+        }
+    }
+
     pub(crate) fn async_event_req(c_id: u16) -> Self {
         Self {
             opcode: 0xC,
@@ -265,25 +408,38 @@ impl NvmeCommand {
         }
     }
 
-    pub(crate) fn get_log_page(
+    // numd is the 0's based number of dwords, offset is in bytes and needs to be dword aligned
+    pub fn get_log_page(
         c_id: u16,
+        ns_id: u32,
         numd: u32,
         ptr0: u64,
         ptr1: u64,
         lid: u8,
-        lpid: u16,
+        offset: u64,
     ) -> Self {
         Self {
+            opcode: 2,
             c_id,
+            ns_id,
             d_ptr: [ptr0, ptr1],
             cdw10: (numd << 16) | lid as u32,
-            cdw11: ((lpid as u32) << 16) | numd >> 16,
+            cdw11: numd >> 16,
+            cdw12: offset as u32,
+            cdw13: (offset >> 32) as u32,
             ..Self::default()
         }
     }
 
-    // TODO this is optional, need to check if it's supported
-    pub fn copy(c_id: u16, ns_id: u32, sdlba: u64, ptr0: u64) -> Self {
+    /// Sets the Log Specific Parameter and the Log Specific Identifier, e.g. the endurance group of FDP log pages
+    pub fn log_specific(mut self, lsp: u8, lsi: u16) -> Self {
+        self.cdw10 = (self.cdw10 & !(0x7F << 8)) | ((lsp as u32 & 0x7F) << 8);
+        self.cdw11 = (self.cdw11 & 0xFFFF) | ((lsi as u32) << 16);
+        self
+    }
+
+    // nr_1 is the 0's based number of source range entries (format 0) at ptr0/ptr1
+    pub fn copy(c_id: u16, ns_id: u32, sdlba: u64, nr_1: u8, ptr0: u64, ptr1: u64) -> Self {
         Self {
             opcode: 0x19,
             flags: 0,
@@ -291,10 +447,10 @@ impl NvmeCommand {
             ns_id,
             _rsvd: 0,
             md_ptr: 0,
-            d_ptr: [ptr0, 0],
+            d_ptr: [ptr0, ptr1],
             cdw10: sdlba as u32,
             cdw11: (sdlba >> 32) as u32,
-            cdw12: 0,
+            cdw12: nr_1 as u32,
             cdw13: 0,
             cdw14: 0,
             cdw15: 0,
@@ -321,6 +477,106 @@ impl NvmeCommand {
         }
     }
 
+    // Directive operations without a data transfer, cdw12 is operation specific
+    pub fn directive_send(c_id: u16, ns_id: u32, doper: u8, dtype: u8, dspec: u16, cdw12: u32) -> Self {
+        Self {
+            opcode: 0x19,
+            c_id,
+            ns_id,
+            cdw11: ((dspec as u32) << 16) | ((dtype as u32) << 8) | doper as u32,
+            cdw12,
+            ..Default::default()
+        }
+    }
+
+    // numd is the 0's based number of dwords, cdw12 is operation specific
+    pub fn directive_receive(c_id: u16, ns_id: u32, ptr: usize, numd: u32, doper: u8, dtype: u8, cdw12: u32) -> Self {
+        Self {
+            opcode: 0x1A,
+            c_id,
+            ns_id,
+            d_ptr: [ptr as u64, 0],
+            cdw10: numd,
+            cdw11: ((dtype as u32) << 8) | doper as u32,
+            cdw12,
+            ..Default::default()
+        }
+    }
+
+    // numd is the 0's based number of dwords, mos is management operation specific
+    pub fn io_management_receive(c_id: u16, ns_id: u32, ptr0: u64, ptr1: u64, numd: u32, mo: u8, mos: u16) -> Self {
+        Self {
+            opcode: 0x12,
+            c_id,
+            ns_id,
+            d_ptr: [ptr0, ptr1],
+            cdw10: ((mos as u32) << 16) | mo as u32,
+            cdw11: numd,
+            ..Default::default()
+        }
+    }
+
+    // For reclaim unit handle update, ptr0/ptr1 point to the placement ids and mos is their 0's based count
+    pub fn io_management_send(c_id: u16, ns_id: u32, ptr0: u64, ptr1: u64, mo: u8, mos: u16) -> Self {
+        Self {
+            opcode: 0x1D,
+            c_id,
+            ns_id,
+            d_ptr: [ptr0, ptr1],
+            cdw10: ((mos as u32) << 16) | mo as u32,
+            ..Default::default()
+        }
+    }
+
+    // ptr0 points to the current and new reservation key, cptpl: 0 = no change, 2 = clear, 3 = set PTPL
+    pub fn reservation_register(c_id: u16, ns_id: u32, ptr0: u64, rrega: u8, iekey: bool, cptpl: u8) -> Self {
+        Self {
+            opcode: 0xD,
+            c_id,
+            ns_id,
+            d_ptr: [ptr0, 0],
+            cdw10: ((cptpl as u32 & 0x3) << 30) | ((iekey as u32) << 3) | (rrega as u32 & 0x7),
+            ..Default::default()
+        }
+    }
+
+    // numd is the 0's based number of dwords, eds requests the extended data structure with 128 bit host ids
+    pub fn reservation_report(c_id: u16, ns_id: u32, ptr0: u64, ptr1: u64, numd: u32, eds: bool) -> Self {
+        Self {
+            opcode: 0xE,
+            c_id,
+            ns_id,
+            d_ptr: [ptr0, ptr1],
+            cdw10: numd,
+            cdw11: eds as u32,
+            ..Default::default()
+        }
+    }
+
+    // ptr0 points to the current and the preempted reservation key
+    pub fn reservation_acquire(c_id: u16, ns_id: u32, ptr0: u64, racqa: u8, iekey: bool, rtype: u8) -> Self {
+        Self {
+            opcode: 0x11,
+            c_id,
+            ns_id,
+            d_ptr: [ptr0, 0],
+            cdw10: ((rtype as u32) << 8) | ((iekey as u32) << 3) | (racqa as u32 & 0x7),
+            ..Default::default()
+        }
+    }
+
+    // ptr0 points to the current reservation key
+    pub fn reservation_release(c_id: u16, ns_id: u32, ptr0: u64, rrela: u8, iekey: bool, rtype: u8) -> Self {
+        Self {
+            opcode: 0x15,
+            c_id,
+            ns_id,
+            d_ptr: [ptr0, 0],
+            cdw10: ((rtype as u32) << 8) | ((iekey as u32) << 3) | (rrela as u32 & 0x7),
+            ..Default::default()
+        }
+    }
+
     pub fn zone_management_send(c_id: u16, ns_id: u32, slba: u64, select_all: bool, zsa: u8, ptr0 : u64) -> Self {
         Self {
             opcode: 0x79,
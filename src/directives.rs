@@ -0,0 +1,70 @@
+// Directives exchanged with the Directive Send and Directive Receive commands
+// See Section 8.7 of the NVMe Base Specification 2.0
+
+pub(crate) const DTYPE_IDENTIFY: u8 = 0;
+pub(crate) const DTYPE_STREAMS: u8 = 1;
+
+// Identify directive operations
+pub(crate) const IDENTIFY_RETURN_PARAMETERS: u8 = 1;
+pub(crate) const IDENTIFY_ENABLE_DIRECTIVE: u8 = 1;
+
+// Streams directive operations, receive and send share values
+pub(crate) const STREAMS_RETURN_PARAMETERS: u8 = 1;
+pub(crate) const STREAMS_GET_STATUS: u8 = 2;
+pub(crate) const STREAMS_ALLOCATE_RESOURCES: u8 = 3;
+pub(crate) const STREAMS_RELEASE_IDENTIFIER: u8 = 1;
+pub(crate) const STREAMS_RELEASE_RESOURCES: u8 = 2;
+
+/// Directive types supported and enabled on a namespace, from the Identify directive Return Parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectiveSupport {
+    pub supported: [u8; 32], // Bit n is set if directive type n is supported
+    pub enabled: [u8; 32],
+    pub persistent: [u8; 32], // Enablement persists across controller level resets
+}
+
+impl DirectiveSupport {
+    pub fn streams_supported(&self) -> bool {
+        (self.supported[0] >> DTYPE_STREAMS) & 1 == 1
+    }
+
+    pub fn streams_enabled(&self) -> bool {
+        (self.enabled[0] >> DTYPE_STREAMS) & 1 == 1
+    }
+
+    pub(crate) fn parse(data: &[u8]) -> Self {
+        Self {
+            supported: data[..32].try_into().unwrap(),
+            enabled: data[32..64].try_into().unwrap(),
+            persistent: data[64..96].try_into().unwrap(),
+        }
+    }
+}
+
+// Streams Directive Return Parameters Data Structure
+// See Figure 467 of the NVMe Base Specification 2.0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamParameters {
+    pub max_streams: u16, // Maximum streams limit of the subsystem
+    pub subsystem_streams_available: u16,
+    pub subsystem_streams_open: u16,
+    pub write_size: u32,  // Stream write size, optimal write size in blocks
+    pub granularity: u16, // Stream granularity size, in units of the write size
+    pub allocated: u16,   // Streams allocated to the namespace
+    pub open: u16,        // Streams of the namespace currently open
+}
+
+impl StreamParameters {
+    pub(crate) fn parse(data: &[u8]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        Self {
+            max_streams: u16_at(0),
+            subsystem_streams_available: u16_at(2),
+            subsystem_streams_open: u16_at(4),
+            write_size: u32::from_le_bytes(data[16..20].try_into().unwrap()),
+            granularity: u16_at(20),
+            allocated: u16_at(22),
+            open: u16_at(24),
+        }
+    }
+}
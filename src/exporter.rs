@@ -0,0 +1,274 @@
+// Metrics exporter serving driver, namespace and ZNS target statistics over HTTP
+// in the Prometheus text exposition format (version 0.0.4)
+
+use crate::nonseq::ZNSTarget;
+use crate::stats::{IoStats, LatencyHistogram, StatsRegistry};
+use crate::trace::io_opcode_name;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Upper bounds of the exported latency buckets in seconds, the driver's histograms are much finer
+const LATENCY_BUCKETS: [f64; 18] = [
+    5e-6, 10e-6, 25e-6, 50e-6, 100e-6, 250e-6, 500e-6, 1e-3, 2.5e-3, 5e-3, 10e-3, 25e-3, 50e-3, 100e-3, 250e-3,
+    500e-3, 1.0, 10.0,
+];
+// How often the serving thread checks whether it should stop
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// What the exporter reports, every source is labelled with its name
+#[derive(Clone, Default)]
+pub struct MetricsSources {
+    pub devices: Vec<(String, StatsRegistry)>, // Only queues with statistics enabled show up
+    pub zns_targets: Vec<(String, Arc<ZNSTarget>)>,
+}
+
+// Samples grouped by metric family, each family needs its HELP and TYPE line exactly once
+#[derive(Default)]
+struct Families {
+    families: Vec<(&'static str, &'static str, &'static str, String)>, // Name, type, help, samples
+    index: HashMap<&'static str, usize>,
+}
+
+impl Families {
+    fn family(&mut self, name: &'static str, kind: &'static str, help: &'static str) -> &mut String {
+        let idx = *self.index.entry(name).or_insert_with(|| {
+            self.families.push((name, kind, help, String::new()));
+            self.families.len() - 1
+        });
+        &mut self.families[idx].3
+    }
+
+    fn counter(&mut self, name: &'static str, help: &'static str, labels: &str, value: u64) {
+        let _ = writeln!(self.family(name, "counter", help), "{name}{{{labels}}} {value}");
+    }
+
+    fn gauge(&mut self, name: &'static str, help: &'static str, labels: &str, value: f64) {
+        let _ = writeln!(self.family(name, "gauge", help), "{name}{{{labels}}} {value}");
+    }
+
+    fn histogram(&mut self, name: &'static str, help: &'static str, labels: &str, histogram: &LatencyHistogram) {
+        let samples = self.family(name, "histogram", help);
+        let mut buckets = histogram.buckets().peekable();
+        let mut cumulative = 0;
+        for le in LATENCY_BUCKETS {
+            while let Some((_, count)) = buckets.next_if(|(upper, _)| upper.as_secs_f64() <= le) {
+                cumulative += count;
+            }
+            let _ = writeln!(samples, "{name}_bucket{{{labels},le=\"{le}\"}} {cumulative}");
+        }
+        let _ = writeln!(samples, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", histogram.count());
+        let _ = writeln!(samples, "{name}_sum{{{labels}}} {}", histogram.sum().as_secs_f64());
+        let _ = writeln!(samples, "{name}_count{{{labels}}} {}", histogram.count());
+    }
+
+    fn io_stats(&mut self, metrics: &IoMetrics, labels: &str, stats: &IoStats) {
+        self.counter(metrics.submissions, "Submitted commands", labels, stats.submissions);
+        self.counter(metrics.completions, "Completed commands", labels, stats.completions);
+        self.counter(metrics.bytes_read, "Bytes read by successful commands", labels, stats.bytes_read);
+        self.counter(metrics.bytes_written, "Bytes written by successful commands", labels, stats.bytes_written);
+        for (opcode, &n) in stats.ops.iter().enumerate().filter(|(_, &n)| n > 0) {
+            let opcode = match io_opcode_name(opcode as u8) {
+                Some(name) => name.to_string(),
+                None => format!("0x{opcode:02x}"),
+            };
+            self.counter(metrics.ops, "Submitted commands by opcode", &format!("{labels},opcode=\"{opcode}\""), n);
+        }
+        for (&status, &n) in &stats.errors {
+            let labels = format!("{labels},sct=\"{}\",sc=\"0x{:02x}\"", status >> 8, status & 0xFF);
+            self.counter(metrics.errors, "Failed completions by status", &labels, n);
+        }
+        self.gauge(metrics.queue_depth_mean, "Mean outstanding commands on submission", labels, stats.mean_queue_depth());
+        self.gauge(metrics.queue_depth_max, "Maximum outstanding commands on submission", labels, stats.max_queue_depth() as f64);
+        for (op, histogram) in [("all", &stats.latency), ("read", &stats.read_latency), ("write", &stats.write_latency)] {
+            let labels = format!("{labels},op=\"{op}\"");
+            self.histogram(metrics.latency, "Submission to completion latency", &labels, histogram);
+        }
+    }
+
+    fn render(self) -> String {
+        let mut out = String::new();
+        for (name, kind, help, samples) in self.families {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            out.push_str(&samples);
+        }
+        out
+    }
+}
+
+// Metric names of queue and namespace statistics
+struct IoMetrics {
+    submissions: &'static str,
+    completions: &'static str,
+    bytes_read: &'static str,
+    bytes_written: &'static str,
+    ops: &'static str,
+    errors: &'static str,
+    queue_depth_mean: &'static str,
+    queue_depth_max: &'static str,
+    latency: &'static str,
+}
+
+const QUEUE_METRICS: IoMetrics = IoMetrics {
+    submissions: "vroom_queue_submissions_total",
+    completions: "vroom_queue_completions_total",
+    bytes_read: "vroom_queue_read_bytes_total",
+    bytes_written: "vroom_queue_written_bytes_total",
+    ops: "vroom_queue_ops_total",
+    errors: "vroom_queue_errors_total",
+    queue_depth_mean: "vroom_queue_depth_mean",
+    queue_depth_max: "vroom_queue_depth_max",
+    latency: "vroom_queue_latency_seconds",
+};
+
+const NAMESPACE_METRICS: IoMetrics = IoMetrics {
+    submissions: "vroom_namespace_submissions_total",
+    completions: "vroom_namespace_completions_total",
+    bytes_read: "vroom_namespace_read_bytes_total",
+    bytes_written: "vroom_namespace_written_bytes_total",
+    ops: "vroom_namespace_ops_total",
+    errors: "vroom_namespace_errors_total",
+    queue_depth_mean: "vroom_namespace_queue_depth_mean",
+    queue_depth_max: "vroom_namespace_queue_depth_max",
+    latency: "vroom_namespace_latency_seconds",
+};
+
+// Label values may not contain unescaped backslashes, quotes or newlines
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Renders the current statistics of `sources` in the Prometheus text format
+pub fn render(sources: &MetricsSources) -> String {
+    let mut families = Families::default();
+    for (name, registry) in &sources.devices {
+        let device = format!("device=\"{}\"", escape(name));
+        let stats = registry.snapshot();
+        families.counter("vroom_submissions_total", "Submitted I/O commands of the device", &device, stats.submissions);
+        families.counter("vroom_completions_total", "Completed I/O commands of the device", &device, stats.completions);
+        for (q_id, queue) in &stats.queues {
+            families.io_stats(&QUEUE_METRICS, &format!("{device},queue=\"{q_id}\""), queue);
+        }
+        for (ns_id, namespace) in &stats.namespaces {
+            families.io_stats(&NAMESPACE_METRICS, &format!("{device},namespace=\"{ns_id}\""), namespace);
+        }
+    }
+
+    for (name, target) in &sources.zns_targets {
+        let labels = format!("target=\"{}\"", escape(name));
+        let stats = target.stats();
+        families.gauge("vroom_zns_exposed_zones", "Zones exposed by the ZNS target", &labels, stats.exposed_zones as f64);
+        for (state, zones) in [("free", stats.free_zones), ("full", stats.full_zones), ("op", stats.op_zones)] {
+            let labels = format!("{labels},state=\"{state}\"");
+            families.gauge("vroom_zns_zones", "Zones of the ZNS target by pool", &labels, zones as f64);
+        }
+        families.counter("vroom_zns_reclaims_total", "Completed zone reclaims", &labels, stats.reclaims);
+        families.counter(
+            "vroom_zns_relocated_blocks_total",
+            "Valid blocks copied out of reclaimed zones",
+            &labels,
+            stats.relocated_blocks,
+        );
+    }
+    families.render()
+}
+
+/// HTTP endpoint serving `render` on `/metrics` from a dedicated thread, stopped when dropped
+pub struct MetricsExporter {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsExporter {
+    /// Starts serving on `addr`, e.g. `127.0.0.1:9100`. Port 0 picks a free port, see `local_addr`.
+    pub fn spawn<A: ToSocketAddrs>(addr: A, sources: MetricsSources) -> Result<Self, Box<dyn Error>> {
+        let listener = TcpListener::bind(addr)?;
+        // Non-blocking, so the thread notices when it should stop
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("vroom-metrics".to_string())
+                .spawn(move || serve(listener, sources, stop))?
+        };
+        Ok(Self {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsExporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(listener: TcpListener, sources: MetricsSources, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = respond(stream, &sources) {
+                    eprintln!("Serving metrics failed: {e}");
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+            Err(e) => {
+                eprintln!("Accepting metrics connection failed: {e}");
+                thread::sleep(ACCEPT_INTERVAL);
+            }
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, sources: &MetricsSources) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    // Only the request line matters, the rest of the header is read and ignored
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+    let (method, path) = (request_line.next(), request_line.next());
+
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4", render(sources)),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Metrics are served on /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
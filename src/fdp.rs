@@ -0,0 +1,195 @@
+// Flexible Data Placement, placing writes into reclaim units through placement identifiers
+// See Section 8.1.28 of the NVMe Base Specification 2.1
+
+pub(crate) const DTYPE_DATA_PLACEMENT: u8 = 2;
+pub(crate) const FID_FLEXIBLE_DATA_PLACEMENT: u8 = 0x1D;
+
+// I/O Management Receive / Send operations
+pub(crate) const MO_RECLAIM_UNIT_HANDLE_STATUS: u8 = 1;
+pub(crate) const MO_RECLAIM_UNIT_HANDLE_UPDATE: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReclaimUnitHandleType {
+    InitiallyIsolated,
+    PersistentlyIsolated,
+    Unknown(u8),
+}
+
+// FDP Configuration Descriptor
+// See Figure 280 of the NVMe Base Specification 2.1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FdpConfiguration {
+    pub valid: bool,
+    pub rgif: u8, // Number of upper placement identifier bits selecting the reclaim group
+    pub volatile_write_cache: bool,
+    pub n_reclaim_groups: u32,
+    pub max_placement_ids: u32, // Placement handles a namespace can have
+    pub n_namespaces: u32,      // Namespaces that can use the configuration
+    pub reclaim_unit_size: u64, // Nominal size in bytes
+    pub reclaim_unit_time_limit: u32, // Seconds until an open reclaim unit is closed, 0 if unlimited
+    pub handle_types: Vec<ReclaimUnitHandleType>, // Indexed by reclaim unit handle id
+}
+
+impl FdpConfiguration {
+    /// Placement identifier of the namespace's `placement_handle` in `reclaim_group`, as written by `write_placement`
+    pub fn placement_id(&self, reclaim_group: u16, placement_handle: u16) -> u16 {
+        if self.rgif == 0 {
+            return placement_handle;
+        }
+        (reclaim_group << (16 - self.rgif as u32)) | placement_handle
+    }
+
+    // Parses the descriptors following the FDP Configurations log page header
+    pub(crate) fn parse_all(data: &[u8]) -> Vec<Self> {
+        let n_configs = u16::from_le_bytes([data[0], data[1]]) as usize + 1;
+        let mut configs = Vec::with_capacity(n_configs);
+        let mut offset = 16;
+        for _ in 0..n_configs {
+            if offset + 64 > data.len() {
+                break;
+            }
+            let desc = &data[offset..];
+            let size = u16::from_le_bytes([desc[0], desc[1]]) as usize;
+            let n_handles = u16::from_le_bytes([desc[8], desc[9]]) as usize;
+            let handle_types = desc[64..]
+                .chunks_exact(4)
+                .take(n_handles)
+                .map(|ruh| match ruh[0] {
+                    1 => ReclaimUnitHandleType::InitiallyIsolated,
+                    2 => ReclaimUnitHandleType::PersistentlyIsolated,
+                    raw => ReclaimUnitHandleType::Unknown(raw),
+                })
+                .collect();
+            configs.push(Self {
+                valid: desc[2] >> 7 == 1,
+                rgif: desc[2] & 0xF,
+                volatile_write_cache: (desc[2] >> 4) & 1 == 1,
+                n_reclaim_groups: u32::from_le_bytes(desc[4..8].try_into().unwrap()),
+                max_placement_ids: u16::from_le_bytes([desc[10], desc[11]]) as u32 + 1,
+                n_namespaces: u32::from_le_bytes(desc[12..16].try_into().unwrap()),
+                reclaim_unit_size: u64::from_le_bytes(desc[16..24].try_into().unwrap()),
+                reclaim_unit_time_limit: u32::from_le_bytes(desc[24..28].try_into().unwrap()),
+                handle_types,
+            });
+            if size == 0 {
+                break;
+            }
+            offset += size;
+        }
+        configs
+    }
+}
+
+/// Who places data into a reclaim unit handle, from the Reclaim Unit Handle Usage log page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReclaimUnitHandleUsage {
+    Unused,
+    HostSpecified,
+    ControllerSpecified,
+    Unknown(u8),
+}
+
+impl From<u8> for ReclaimUnitHandleUsage {
+    fn from(raw: u8) -> Self {
+        match raw {
+            0 => ReclaimUnitHandleUsage::Unused,
+            1 => ReclaimUnitHandleUsage::HostSpecified,
+            2 => ReclaimUnitHandleUsage::ControllerSpecified,
+            raw => ReclaimUnitHandleUsage::Unknown(raw),
+        }
+    }
+}
+
+// FDP Statistics Log Page
+// See Figure 284 of the NVMe Base Specification 2.1
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct FdpStatistics {
+    pub host_bytes_written: u128, // Including metadata
+    pub media_bytes_written: u128,
+    pub media_bytes_erased: u128,
+    _rsvd: [u8; 16],
+}
+
+impl FdpStatistics {
+    /// Write amplification since FDP was enabled, media over host bytes written
+    pub fn write_amplification(&self) -> f64 {
+        let host = self.host_bytes_written;
+        let media = self.media_bytes_written;
+        if host == 0 {
+            return 0.0;
+        }
+        media as f64 / host as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdpEventType {
+    ReclaimUnitNotFullyWritten,
+    ReclaimUnitTimeLimitExceeded,
+    ControllerResetModifiedHandles,
+    InvalidPlacementId,
+    MediaReallocated,
+    ImplicitlyModifiedHandle,
+    Unknown(u8),
+}
+
+// FDP Event Data Structure
+// See Figure 286 of the NVMe Base Specification 2.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FdpEvent {
+    pub event_type: FdpEventType,
+    pub placement_id: Option<u16>,
+    pub timestamp: u64, // Milliseconds since the epoch, in the format of the Timestamp feature
+    pub ns_id: Option<u32>,
+    pub reclaim_group: u16,
+    pub handle_id: u16,
+    pub type_specific: [u8; 16],
+}
+
+impl FdpEvent {
+    pub(crate) fn parse(data: &[u8]) -> Self {
+        let flags = data[1];
+        let event_type = match data[0] {
+            0x00 => FdpEventType::ReclaimUnitNotFullyWritten,
+            0x01 => FdpEventType::ReclaimUnitTimeLimitExceeded,
+            0x02 => FdpEventType::ControllerResetModifiedHandles,
+            0x03 => FdpEventType::InvalidPlacementId,
+            0x80 => FdpEventType::MediaReallocated,
+            0x81 => FdpEventType::ImplicitlyModifiedHandle,
+            raw => FdpEventType::Unknown(raw),
+        };
+        let mut timestamp = [0u8; 8];
+        timestamp[..6].copy_from_slice(&data[4..10]);
+        Self {
+            event_type,
+            placement_id: (flags & 1 == 1).then(|| u16::from_le_bytes([data[2], data[3]])),
+            timestamp: u64::from_le_bytes(timestamp),
+            ns_id: ((flags >> 1) & 1 == 1).then(|| u32::from_le_bytes(data[12..16].try_into().unwrap())),
+            type_specific: data[16..32].try_into().unwrap(),
+            reclaim_group: u16::from_le_bytes([data[32], data[33]]),
+            handle_id: data[34] as u16,
+        }
+    }
+}
+
+// Reclaim Unit Handle Status Descriptor
+// See Figure 577 of the NVMe Base Specification 2.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReclaimUnitHandleStatus {
+    pub placement_id: u16,
+    pub handle_id: u16,
+    pub time_remaining: u32,   // Estimated seconds until the active reclaim unit is closed
+    pub available_writes: u64, // Logical blocks that can still be written to the active reclaim unit
+}
+
+impl ReclaimUnitHandleStatus {
+    pub(crate) fn parse(data: &[u8]) -> Self {
+        Self {
+            placement_id: u16::from_le_bytes([data[0], data[1]]),
+            handle_id: u16::from_le_bytes([data[2], data[3]]),
+            time_remaining: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            available_writes: u64::from_le_bytes(data[8..16].try_into().unwrap()),
+        }
+    }
+}
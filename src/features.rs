@@ -0,0 +1,212 @@
+// Features accessed with the Get Features and Set Features commands
+// See Section 5.27.1 of the NVMe Base Specification 2.0
+
+/// Feature Identifiers of the standard features
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureId {
+    Arbitration = 0x01,
+    PowerManagement = 0x02,
+    TemperatureThreshold = 0x04,
+    ErrorRecovery = 0x05,
+    VolatileWriteCache = 0x06,
+    NumberOfQueues = 0x07,
+    InterruptCoalescing = 0x08,
+    WriteAtomicity = 0x0A,
+    AsyncEventConfig = 0x0B,
+    AutonomousPowerStateTransition = 0x0C,
+    Timestamp = 0x0E,
+    HostBehavior = 0x16,
+    HostIdentifier = 0x81,
+}
+
+/// Which value Get Features returns
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FeatureSelect {
+    #[default]
+    Current = 0,
+    Default = 1,
+    Saved = 2,
+}
+
+/// Capabilities of a feature, returned by Get Features with the supported capabilities select
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeatureCapabilities {
+    pub saveable: bool,
+    pub namespace_specific: bool,
+    pub changeable: bool,
+}
+
+impl From<u32> for FeatureCapabilities {
+    fn from(raw: u32) -> Self {
+        Self {
+            saveable: raw & 1 == 1,
+            namespace_specific: (raw >> 1) & 1 == 1,
+            changeable: (raw >> 2) & 1 == 1,
+        }
+    }
+}
+
+/// Value of a standard feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::large_enum_variant)]
+pub enum Feature {
+    /// Arbitration burst as a power of two (7 = no limit) and 0's based weights of the priority classes
+    Arbitration { burst: u8, low: u8, medium: u8, high: u8 },
+    PowerManagement { power_state: u8, workload_hint: u8 },
+    /// Threshold in Kelvin for `sensor` (0 = composite), `under` selects the under temperature threshold
+    TemperatureThreshold { threshold: u16, sensor: u8, under: bool },
+    /// Time limited error recovery in 100ms units, 0 disables it
+    ErrorRecovery { time_limit: u16, deallocated_error: bool },
+    VolatileWriteCache(bool),
+    /// Number of I/O submission and completion queues, the controller's allocation is returned by set
+    NumberOfQueues { submission: u16, completion: u16 },
+    /// Aggregation threshold in completions (0's based) and aggregation time in 100us units
+    InterruptCoalescing { threshold: u8, time: u8 },
+    /// Disables normal atomicity so only AWUPF applies
+    WriteAtomicity(bool),
+    AsyncEventConfig(u32),
+    /// APST enable and the 32 entry transition table, one u64 per power state
+    AutonomousPowerStateTransition { enabled: bool, table: [u64; 32] },
+    /// Milliseconds since the epoch, only the lower 48 bits are used
+    Timestamp(u64),
+    HostBehavior { advanced_command_retry: bool },
+    /// Identifies the host in reservations, `extended` selects the 128 bit instead of the 64 bit format
+    HostIdentifier { id: [u8; 16], extended: bool },
+}
+
+impl Feature {
+    pub fn id(&self) -> FeatureId {
+        match self {
+            Feature::Arbitration { .. } => FeatureId::Arbitration,
+            Feature::PowerManagement { .. } => FeatureId::PowerManagement,
+            Feature::TemperatureThreshold { .. } => FeatureId::TemperatureThreshold,
+            Feature::ErrorRecovery { .. } => FeatureId::ErrorRecovery,
+            Feature::VolatileWriteCache(_) => FeatureId::VolatileWriteCache,
+            Feature::NumberOfQueues { .. } => FeatureId::NumberOfQueues,
+            Feature::InterruptCoalescing { .. } => FeatureId::InterruptCoalescing,
+            Feature::WriteAtomicity(_) => FeatureId::WriteAtomicity,
+            Feature::AsyncEventConfig(_) => FeatureId::AsyncEventConfig,
+            Feature::AutonomousPowerStateTransition { .. } => FeatureId::AutonomousPowerStateTransition,
+            Feature::Timestamp(_) => FeatureId::Timestamp,
+            Feature::HostBehavior { .. } => FeatureId::HostBehavior,
+            Feature::HostIdentifier { .. } => FeatureId::HostIdentifier,
+        }
+    }
+
+    // Returns command dword 11 and the data to transfer for Set Features
+    pub(crate) fn encode(&self) -> (u32, Vec<u8>) {
+        match *self {
+            Feature::Arbitration { burst, low, medium, high } => (
+                (high as u32) << 24 | (medium as u32) << 16 | (low as u32) << 8 | (burst as u32 & 0x7),
+                Vec::new(),
+            ),
+            Feature::PowerManagement { power_state, workload_hint } => {
+                (((workload_hint as u32 & 0x7) << 5) | (power_state as u32 & 0x1F), Vec::new())
+            }
+            Feature::TemperatureThreshold { threshold, sensor, under } => (
+                ((under as u32) << 20) | ((sensor as u32 & 0xF) << 16) | threshold as u32,
+                Vec::new(),
+            ),
+            Feature::ErrorRecovery { time_limit, deallocated_error } => {
+                (((deallocated_error as u32) << 16) | time_limit as u32, Vec::new())
+            }
+            Feature::VolatileWriteCache(enabled) => (enabled as u32, Vec::new()),
+            Feature::NumberOfQueues { submission, completion } => (
+                ((completion.max(1) - 1) as u32) << 16 | (submission.max(1) - 1) as u32,
+                Vec::new(),
+            ),
+            Feature::InterruptCoalescing { threshold, time } => (((time as u32) << 8) | threshold as u32, Vec::new()),
+            Feature::WriteAtomicity(disable_normal) => (disable_normal as u32, Vec::new()),
+            Feature::AsyncEventConfig(config) => (config, Vec::new()),
+            Feature::AutonomousPowerStateTransition { enabled, table } => {
+                (enabled as u32, table.iter().flat_map(|entry| entry.to_le_bytes()).collect())
+            }
+            Feature::Timestamp(ms) => {
+                let mut data = ms.to_le_bytes();
+                data[6] = 0;
+                data[7] = 0;
+                (0, data.to_vec())
+            }
+            Feature::HostBehavior { advanced_command_retry } => {
+                let mut data = vec![0u8; 512];
+                data[0] = advanced_command_retry as u8;
+                (0, data)
+            }
+            Feature::HostIdentifier { id, extended } => {
+                let len = if extended { 16 } else { 8 };
+                (extended as u32, id[..len].to_vec())
+            }
+        }
+    }
+
+    // Builds the feature from command dword 11, completion dword 0 and the data returned by Get Features
+    pub(crate) fn decode(id: FeatureId, cdw11: u32, dw0: u32, data: &[u8]) -> Self {
+        match id {
+            FeatureId::Arbitration => Feature::Arbitration {
+                burst: (dw0 & 0x7) as u8,
+                low: (dw0 >> 8) as u8,
+                medium: (dw0 >> 16) as u8,
+                high: (dw0 >> 24) as u8,
+            },
+            FeatureId::PowerManagement => Feature::PowerManagement {
+                power_state: (dw0 & 0x1F) as u8,
+                workload_hint: ((dw0 >> 5) & 0x7) as u8,
+            },
+            FeatureId::TemperatureThreshold => Feature::TemperatureThreshold {
+                threshold: dw0 as u16,
+                sensor: ((dw0 >> 16) & 0xF) as u8,
+                under: (dw0 >> 20) & 0x3 == 1,
+            },
+            FeatureId::ErrorRecovery => Feature::ErrorRecovery {
+                time_limit: dw0 as u16,
+                deallocated_error: (dw0 >> 16) & 1 == 1,
+            },
+            FeatureId::VolatileWriteCache => Feature::VolatileWriteCache(dw0 & 1 == 1),
+            FeatureId::NumberOfQueues => Feature::NumberOfQueues {
+                submission: (dw0 & 0xFFFF) as u16 + 1,
+                completion: (dw0 >> 16) as u16 + 1,
+            },
+            FeatureId::InterruptCoalescing => Feature::InterruptCoalescing {
+                threshold: dw0 as u8,
+                time: (dw0 >> 8) as u8,
+            },
+            FeatureId::WriteAtomicity => Feature::WriteAtomicity(dw0 & 1 == 1),
+            FeatureId::AsyncEventConfig => Feature::AsyncEventConfig(dw0),
+            FeatureId::AutonomousPowerStateTransition => {
+                let mut table = [0u64; 32];
+                for (entry, bytes) in table.iter_mut().zip(data.chunks_exact(8)) {
+                    *entry = u64::from_le_bytes(bytes.try_into().unwrap());
+                }
+                Feature::AutonomousPowerStateTransition { enabled: dw0 & 1 == 1, table }
+            }
+            FeatureId::Timestamp => {
+                let mut bytes = [0u8; 8];
+                bytes[..6].copy_from_slice(&data[..6]);
+                Feature::Timestamp(u64::from_le_bytes(bytes))
+            }
+            FeatureId::HostBehavior => Feature::HostBehavior {
+                advanced_command_retry: data[0] & 1 == 1,
+            },
+            FeatureId::HostIdentifier => {
+                let extended = cdw11 & 1 == 1;
+                let mut id = [0u8; 16];
+                let len = if extended { 16 } else { 8 };
+                id[..len].copy_from_slice(&data[..len]);
+                Feature::HostIdentifier { id, extended }
+            }
+        }
+    }
+}
+
+impl FeatureId {
+    // Number of bytes transferred in the data buffer
+    pub(crate) fn data_len(&self) -> usize {
+        match self {
+            FeatureId::AutonomousPowerStateTransition => 256,
+            FeatureId::Timestamp => 8,
+            FeatureId::HostBehavior => 512,
+            FeatureId::HostIdentifier => 16,
+            _ => 0,
+        }
+    }
+}
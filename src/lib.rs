@@ -13,12 +13,28 @@ mod queues;
 mod zns;
 #[allow(dead_code)]
 pub mod nonseq;
+pub mod log_page;
+pub mod features;
+pub mod directives;
+pub mod fdp;
+pub mod power;
+pub mod reservation;
+pub mod replay;
+pub mod stats;
+pub mod exporter;
+pub mod poll_group;
+pub mod runtime;
+pub mod qos;
+pub mod trace;
 
+pub use cmd::NvmeCommand;
 pub use memory::HUGE_PAGE_SIZE;
-pub use nvme::{NvmeDevice, NvmeQueuePair};
+pub use nvme::{DoorbellConfig, DoorbellStats, NvmeDevice, NvmeQueuePair, SubmitMode, Submission};
 use pci::*;
-pub use queues::QUEUE_LENGTH;
+pub use queues::{NvmeCompletion, MAX_QUEUE_LENGTH, QUEUE_LENGTH};
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::time::Duration;
 
 pub fn init(pci_addr: &str) -> Result<NvmeDevice, Box<dyn Error>> {
     let mut vendor_file = pci_open_resource_ro(pci_addr, "vendor").expect("wrong pci address");
@@ -45,6 +61,10 @@ pub struct NvmeNamespace {
     pub blocks: u64,
     pub block_size: u64,
     pub flba_idx: u8, //LBA Format index
+    pub mssrl: u16, //Maximum Single Source Range Length of the copy command, in blocks
+    pub mcl: u32, //Maximum Copy Length, in blocks
+    pub msrc: u8, //Maximum Source Range Count, 0's based
+    pub endgid: u16, //Endurance Group the namespace belongs to, 0 if not reported
     pub zns_info : Option<NvmeZNSInfo>
 }
 
@@ -58,6 +78,19 @@ pub struct NvmeZNSInfo {
 pub struct NvmeStats {
     pub completions: u64,
     pub submissions: u64,
+    pub queues: BTreeMap<u16, stats::IoStats>, // By I/O queue id
+    pub namespaces: BTreeMap<u32, stats::IoStats>, // Merged over all I/O queues
+}
+
+/// Priority class of an I/O submission queue under weighted round robin arbitration
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueuePriority {
+    /// Served before all other classes, only limited by the arbitration burst
+    Urgent = 0,
+    High = 1,
+    #[default]
+    Medium = 2,
+    Low = 3,
 }
 
 pub enum ZnsZsa {
@@ -68,3 +101,129 @@ pub enum ZnsZsa {
 	OfflineZone = 5
 	// Set Zone Descriptor Extension isn't supported for now, but might be a useful feature
 }
+
+/// Secure Erase Settings of the Format NVM command
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SecureErase {
+    #[default]
+    None = 0,
+    UserData = 1,
+    Crypto = 2,
+}
+
+/// Options for `NvmeDevice::format`
+#[derive(Debug, Clone, Copy)]
+pub struct FormatOptions {
+    pub lba_format: u8, //Index into the namespace's LBA format list
+    pub extended_metadata: bool, //Metadata transferred as part of the LBA instead of a separate buffer
+    pub pi_type: u8, //Protection information type, 0 disables PI
+    pub pi_first: bool, //PI in the first instead of the last bytes of the metadata
+    pub secure_erase: SecureErase,
+    pub timeout: Duration,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            lba_format: 0,
+            extended_metadata: false,
+            pi_type: 0,
+            pi_first: false,
+            secure_erase: SecureErase::None,
+            timeout: Duration::from_secs(30 * 60),
+        }
+    }
+}
+
+/// Sanitize operation, see the Sanitize command in the NVMe Base Specification
+#[derive(Debug, Clone, Copy)]
+pub enum SanitizeAction {
+    BlockErase,
+    CryptoErase,
+    /// Overwrites with `pattern` for `passes` passes (1 to 16), inverting the pattern between passes if `invert` is set
+    Overwrite { pattern: u32, passes: u8, invert: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizeState {
+    NeverSanitized,
+    Completed,
+    InProgress,
+    Failed,
+    CompletedNoDeallocate,
+    Unknown(u8),
+}
+
+/// Parsed Sanitize Status log page (LID 0x81)
+#[derive(Debug, Clone, Copy)]
+pub struct SanitizeStatus {
+    pub progress: u16, //Fraction of the operation completed, numerator of x/65536
+    pub state: SanitizeState,
+    pub overwrite_passes: u8, //Completed passes of the current overwrite operation
+    pub global_data_erased: bool,
+    pub cdw10: u32, //Command dword 10 of the last sanitize command
+}
+
+impl SanitizeStatus {
+    pub fn percent(&self) -> f32 {
+        if self.state == SanitizeState::InProgress {
+            self.progress as f32 * 100.0 / 65536.0
+        } else {
+            100.0
+        }
+    }
+}
+
+/// Commit Action of the Firmware Commit command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareCommitAction {
+    Replace = 0,
+    ReplaceAndActivate = 1,
+    Activate = 2,
+    ReplaceAndActivateImmediately = 3,
+}
+
+/// What is needed for a committed firmware image to run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareActivation {
+    NotActivated,
+    Activated,
+    NextReset,
+    ConventionalReset,
+    SubsystemReset,
+    ControllerReset,
+}
+
+impl FirmwareActivation {
+    pub fn reset_required(&self) -> bool {
+        !matches!(self, FirmwareActivation::NotActivated | FirmwareActivation::Activated)
+    }
+}
+
+/// Parsed Firmware Slot Information log page (LID 0x03)
+#[derive(Debug, Clone)]
+pub struct FirmwareSlotInfo {
+    pub active_slot: u8,
+    pub next_slot: Option<u8>, //Slot activated at the next reset, if any
+    pub revisions: Vec<String>, //Firmware revision of slots 1 to 7, empty if unused
+    pub n_slots: u8,
+    pub slot1_read_only: bool,
+    pub activation_without_reset: bool,
+}
+
+/// Decoded completion of an Asynchronous Event Request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsyncEvent {
+    Error { info: u8 },
+    ReliabilityDegraded,
+    TemperatureThreshold,
+    SpareBelowThreshold,
+    SmartHealth { info: u8 },
+    NamespaceAttributeChanged(Option<Vec<u32>>), //Changed namespaces, None if more than 1024 changed
+    FirmwareActivationStarting,
+    TelemetryLogChanged,
+    Notice { info: u8 },
+    IoCommandSpecific { info: u8 },
+    VendorSpecific { info: u8 },
+    Other { event_type: u8, info: u8 },
+}
@@ -0,0 +1,140 @@
+// Log pages returned by the Get Log Page command
+// See Section 5.16 of the NVMe Base Specification 2.0
+
+pub const LID_ERROR_INFORMATION: u8 = 0x01;
+pub const LID_SMART_HEALTH: u8 = 0x02;
+pub const LID_FIRMWARE_SLOT: u8 = 0x03;
+pub const LID_CHANGED_NAMESPACES: u8 = 0x04;
+pub const LID_COMMAND_EFFECTS: u8 = 0x05;
+pub const LID_FDP_CONFIGURATIONS: u8 = 0x20;
+pub const LID_RECLAIM_UNIT_HANDLE_USAGE: u8 = 0x21;
+pub const LID_FDP_STATISTICS: u8 = 0x22;
+pub const LID_FDP_EVENTS: u8 = 0x23;
+pub const LID_SANITIZE_STATUS: u8 = 0x81;
+
+// SMART / Health Information Log Page
+// See Figure 207 of the NVMe Base Specification 2.0
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SmartHealthLog {
+    pub critical_warning: u8,
+    pub composite_temperature: u16, // in Kelvin
+    pub available_spare: u8,        // in percent
+    pub available_spare_threshold: u8,
+    pub percentage_used: u8,
+    pub endurance_group_warning: u8,
+    _rsvd1: [u8; 25],
+    pub data_units_read: u128, // in thousands of 512 byte units
+    pub data_units_written: u128,
+    pub host_read_commands: u128,
+    pub host_write_commands: u128,
+    pub controller_busy_time: u128, // in minutes
+    pub power_cycles: u128,
+    pub power_on_hours: u128,
+    pub unsafe_shutdowns: u128,
+    pub media_errors: u128,
+    pub error_log_entries: u128,
+    pub warning_temperature_time: u32, // in minutes
+    pub critical_temperature_time: u32,
+    pub temperature_sensors: [u16; 8], // in Kelvin, 0 if not implemented
+    pub thermal_transitions: [u32; 2],
+    pub thermal_time: [u32; 2],
+    _rsvd2: [u8; 280],
+}
+
+impl SmartHealthLog {
+    pub fn temperature_celsius(&self) -> i32 {
+        self.composite_temperature as i32 - 273
+    }
+
+    pub fn spare_below_threshold(&self) -> bool {
+        self.critical_warning & 1 == 1
+    }
+
+    pub fn temperature_warning(&self) -> bool {
+        (self.critical_warning >> 1) & 1 == 1
+    }
+
+    pub fn reliability_degraded(&self) -> bool {
+        (self.critical_warning >> 2) & 1 == 1
+    }
+
+    pub fn read_only(&self) -> bool {
+        (self.critical_warning >> 3) & 1 == 1
+    }
+}
+
+// Error Information Log Entry
+// See Figure 205 of the NVMe Base Specification 2.0
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorInformationEntry {
+    pub error_count: u64,
+    pub sq_id: u16,
+    pub c_id: u16,
+    pub status: u16, // Status field of the failed command's completion, including the phase bit
+    pub parameter_location: u16,
+    pub lba: u64,
+    pub ns_id: u32,
+    pub vendor_log_page: u8,
+    pub transport_type: u8,
+    _rsvd1: u16,
+    pub command_specific: u64,
+    pub transport_specific: u16,
+    _rsvd2: [u8; 22],
+}
+
+impl ErrorInformationEntry {
+    pub fn status_code(&self) -> u8 {
+        ((self.status >> 1) & 0xFF) as u8
+    }
+
+    pub fn status_code_type(&self) -> u8 {
+        ((self.status >> 9) & 0x7) as u8
+    }
+}
+
+// Commands Supported and Effects Log Page
+// See Figure 210 of the NVMe Base Specification 2.0
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct CommandEffectsLog {
+    pub admin: [u32; 256],
+    pub io: [u32; 256],
+    _rsvd: [u8; 2048],
+}
+
+impl CommandEffectsLog {
+    pub fn admin_effects(&self, opcode: u8) -> CommandEffects {
+        CommandEffects::from(self.admin[opcode as usize])
+    }
+
+    pub fn io_effects(&self, opcode: u8) -> CommandEffects {
+        CommandEffects::from(self.io[opcode as usize])
+    }
+}
+
+// Commands Supported and Effects Data Structure
+// See Figure 211 of the NVMe Base Specification 2.0
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandEffects {
+    pub supported: bool,
+    pub lba_content_change: bool,
+    pub namespace_capability_change: bool,
+    pub namespace_inventory_change: bool,
+    pub controller_capability_change: bool,
+    pub submission_execution: u8, // 0 = no restriction, 1 = per namespace, 2 = per controller
+}
+
+impl From<u32> for CommandEffects {
+    fn from(raw: u32) -> Self {
+        Self {
+            supported: raw & 1 == 1,
+            lba_content_change: (raw >> 1) & 1 == 1,
+            namespace_capability_change: (raw >> 2) & 1 == 1,
+            namespace_inventory_change: (raw >> 3) & 1 == 1,
+            controller_capability_change: (raw >> 4) & 1 == 1,
+            submission_execution: ((raw >> 16) & 0x7) as u8,
+        }
+    }
+}
@@ -251,3 +251,55 @@ pub(crate) fn virt_to_phys(addr: usize) -> Result<usize, Box<dyn Error>> {
 pub fn vfio_enabled() -> bool {
     unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR.is_some() }
 }
+
+/// Fixed size buffers carved out of huge pages, every buffer is physically contiguous and page aligned.
+/// The huge pages are faulted in by the allocating thread, so its NUMA memory policy decides their node.
+pub struct DmaPool {
+    free: Vec<Dma<u8>>,
+    buffer_size: usize,
+    capacity: usize,
+}
+
+impl DmaPool {
+    pub fn new(buffer_size: usize, count: usize) -> Result<Self, Box<dyn Error>> {
+        if buffer_size == 0 || buffer_size > HUGE_PAGE_SIZE {
+            return Err(format!("DMA pool buffers need to fit into a huge page, got {buffer_size} bytes").into());
+        }
+        let stride = buffer_size.div_ceil(4096) * 4096;
+        let per_page = HUGE_PAGE_SIZE / stride;
+        let mut free = Vec::with_capacity(count);
+        while free.len() < count {
+            let page: Dma<u8> = Dma::allocate(HUGE_PAGE_SIZE)?;
+            for i in 0..per_page.min(count - free.len()) {
+                free.push(page.slice(i * stride..i * stride + buffer_size));
+            }
+        }
+        Ok(Self {
+            free,
+            buffer_size,
+            capacity: count,
+        })
+    }
+
+    pub fn get(&mut self) -> Option<Dma<u8>> {
+        self.free.pop()
+    }
+
+    /// Returns a buffer taken with `get`
+    pub fn put(&mut self, buffer: Dma<u8>) {
+        debug_assert_eq!(buffer.size, self.buffer_size);
+        self.free.push(buffer);
+    }
+
+    pub fn available(&self) -> usize {
+        self.free.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+}
@@ -1,7 +1,7 @@
-use crate::{NvmeDevice, NvmeQueuePair, NvmeZNSInfo, ZnsZsa, HUGE_PAGE_SIZE};
+use crate::{NvmeDevice, NvmeQueuePair, NvmeZNSInfo, Submission, ZnsZsa, HUGE_PAGE_SIZE};
 use crate::memory::{Dma, DmaSlice};
 use std::error::Error;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Mutex, RwLock, Condvar};
 
 const ZNS_MAP_UNMAPPED: u64 = 0xFFFFFFFFFFFFFFFF;
@@ -242,7 +242,28 @@ pub struct ZNSTarget {
     reclaim_buffer: Dma<u8>,
     reclaim_locks: Vec<RwLock<()>>,
     reclaim_condition: Condvar,
-    pub end_reclaim: AtomicBool
+    pub end_reclaim: AtomicBool,
+    reclaims: AtomicU64, //Completed reclaims
+    relocated_blocks: AtomicU64 //Valid blocks copied out of reclaimed zones
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZNSTargetStats {
+    pub exposed_zones: u64,
+    pub free_zones: u64,
+    pub full_zones: u64,
+    pub op_zones: u64, //Overprovisioning zones, reclaim destinations
+    pub reclaims: u64,
+    pub relocated_blocks: u64
+}
+
+// Commands to complete for a submission, a partial one is an error once everything queued before completed
+fn outstanding(nvme_queue_pair: &mut NvmeQueuePair, submission: Submission, queued: usize) -> Result<usize, Box<dyn Error>> {
+    let reqs = queued + submission.outstanding();
+    if !submission.is_complete() && reqs > 0 {
+        nvme_queue_pair.complete_io(reqs);
+    }
+    Ok(submission.ensure_complete()?.outstanding())
 }
 
 // TODO
@@ -311,7 +332,9 @@ impl ZNSTarget {
             reclaim_buffer: Dma::allocate(HUGE_PAGE_SIZE)?,
             reclaim_locks,
             reclaim_condition: Condvar::new(),
-            end_reclaim: AtomicBool::new(false)
+            end_reclaim: AtomicBool::new(false),
+            reclaims: AtomicU64::new(0),
+            relocated_blocks: AtomicU64::new(0)
         };
 
         Ok(dev)
@@ -605,6 +628,7 @@ impl ZNSTarget {
                 }
                 //self.backing.copy(self.ns_id, victim_block, op_zone.wp, valid_len)?;
                 self.map.lock().unwrap().remap(victim_block, op_zone.wp, valid_len);                
+                self.relocated_blocks.fetch_add(valid_len, Ordering::Relaxed);
                 op_zone.incr_wp(valid_len)?;
                 victim_block += valid_len;
             }
@@ -624,6 +648,7 @@ impl ZNSTarget {
         victim.reset();
         victim_metadata.reset();
         self.zones.lock().unwrap().op_zones.push(victim);
+        self.reclaims.fetch_add(1, Ordering::Relaxed);
         
         Ok(())
     }         
@@ -678,13 +703,20 @@ impl ZNSTarget {
                 // Append valid_len blocks from victim to op_zone and update wp
                 // Note: this is making the assumptions that all zones have the same capacity
                 for i in 0..valid_len { // Unfortunately the copy command is not supported
-                    let reqs = nvme_queue_pair.submit_io(self.ns_id, self.block_size, &self.reclaim_buffer.slice(0..self.block_size as usize), victim_block + i, false);
-                    nvme_queue_pair.complete_io(reqs);
-                    let reqs = nvme_queue_pair.submit_io(self.ns_id, self.block_size, &self.reclaim_buffer.slice(0..self.block_size as usize), op_zone.wp + i, true);
-                    nvme_queue_pair.complete_io(reqs);
+                    let submission = nvme_queue_pair.submit_io(self.ns_id, self.block_size, &self.reclaim_buffer.slice(0..self.block_size as usize), victim_block + i, false);
+                    let reqs = outstanding(nvme_queue_pair, submission, 0)?;
+                    if reqs > 0 {
+                        nvme_queue_pair.complete_io(reqs);
+                    }
+                    let submission = nvme_queue_pair.submit_io(self.ns_id, self.block_size, &self.reclaim_buffer.slice(0..self.block_size as usize), op_zone.wp + i, true);
+                    let reqs = outstanding(nvme_queue_pair, submission, 0)?;
+                    if reqs > 0 {
+                        nvme_queue_pair.complete_io(reqs);
+                    }
                 }
                 //nvme_queue_pair.copy(self.ns_id, victim_block, op_zone.wp, valid_len, buffer);
                 self.map.lock().unwrap().remap(victim_block, op_zone.wp, valid_len);                
+                self.relocated_blocks.fetch_add(valid_len, Ordering::Relaxed);
                 op_zone.incr_wp(valid_len)?;
                 victim_block += valid_len;
             }
@@ -700,11 +732,13 @@ impl ZNSTarget {
 
         // The victim block is now free and can be reset and added to the overprovisioning zones.
         // and The overprovisioning zone can now be used as a free zone
-        nvme_queue_pair.zone_action(self.ns_id, victim.zslba, ZnsZsa::ResetZone);
-        nvme_queue_pair.complete_io(1);
+        if nvme_queue_pair.zone_action(self.ns_id, victim.zslba, ZnsZsa::ResetZone)?.outstanding() > 0 {
+            nvme_queue_pair.complete_io(1);
+        }
         victim.reset();
         self.zones_metadata[victim_zone_number].lock().unwrap().reset();
         self.zones.lock().unwrap().op_zones.push(victim);
+        self.reclaims.fetch_add(1, Ordering::Relaxed);
         
         Ok(())
     }        
@@ -738,7 +772,8 @@ impl ZNSTarget {
                     
                     let split_index = Ord::min((length_contiguous * self.block_size) as usize, current_array.size);
         
-                    reqs += nvme_queue_pair.submit_io(self.ns_id, self.block_size, &current_array.slice(0..split_index), backing_block, false);
+                    let submission = nvme_queue_pair.submit_io(self.ns_id, self.block_size, &current_array.slice(0..split_index), backing_block, false);
+                    reqs += outstanding(nvme_queue_pair, submission, reqs)?;
 
                     rest = current_array.slice(split_index..current_array.size);
                     current_array = &rest;
@@ -791,8 +826,11 @@ impl ZNSTarget {
             // Idea ignore d_lba and assume it's the write pointer, should always work out? Worth a try
             // TODO Otherwise qd > 1 is gonna be impossible :(
             //reqs += nvme_queue_pair.append_io(self.ns_id, self.block_size, &current_array.slice(0..split_index), current_zone.zslba);
-            let reqs = nvme_queue_pair.submit_io(self.ns_id, self.block_size, &current_array.slice(0..split_index), current_zone.wp, true);
-            nvme_queue_pair.complete_io(reqs);
+            let submission = nvme_queue_pair.submit_io(self.ns_id, self.block_size, &current_array.slice(0..split_index), current_zone.wp, true);
+            let reqs = outstanding(nvme_queue_pair, submission, 0)?;
+            if reqs > 0 {
+                nvme_queue_pair.complete_io(reqs);
+            }
 
             let mut map = self.map.lock().unwrap();
             let backing_block = map.lookup(current_lba);
@@ -831,6 +869,19 @@ impl ZNSTarget {
         self.reclaim_condition.notify_all();
     }
 
+    // Snapshot of the zone pools and reclaim activity, safe to call while other threads use the target
+    pub fn stats(&self) -> ZNSTargetStats {
+        let zones = self.zones.lock().unwrap();
+        ZNSTargetStats {
+            exposed_zones: self.exposed_zones,
+            free_zones: zones.free_zones.len() as u64,
+            full_zones: zones.full_zones.len() as u64,
+            op_zones: zones.op_zones.len() as u64,
+            reclaims: self.reclaims.load(Ordering::Relaxed),
+            relocated_blocks: self.relocated_blocks.load(Ordering::Relaxed)
+        }
+    }
+
     fn get_zone_number(&self, lba: u64) -> usize {
         (lba / self.zns_info.zone_size) as usize
     }
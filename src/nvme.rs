@@ -1,12 +1,25 @@
 use crate::cmd::NvmeCommand;
+use crate::directives::*;
+use crate::fdp::*;
+use crate::features::*;
+use crate::log_page::*;
 use crate::memory::{Dma, DmaSlice};
 use crate::pci::pci_map_resource;
+use crate::power::*;
 use crate::queues::*;
+use crate::reservation::*;
+use crate::stats::{IoStats, StatsRegistry};
+use crate::trace::{SharedTrace, TraceRing};
 use crate::zns::*;
-use crate::{NvmeNamespace, NvmeZNSInfo, NvmeStats, HUGE_PAGE_SIZE, ZnsZsa};
-use std::collections::HashMap;
+use crate::{
+    AsyncEvent, FirmwareActivation, FirmwareCommitAction, FirmwareSlotInfo, FormatOptions, NvmeNamespace, NvmeZNSInfo, NvmeStats, SanitizeAction, SanitizeState, SanitizeStatus,
+    QueuePriority, HUGE_PAGE_SIZE, ZnsZsa,
+};
+use std::collections::{BTreeSet, HashMap};
 use std::error::Error;
 use std::hint::spin_loop;
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
 
 // clippy doesnt like this
 #[allow(unused, clippy::upper_case_acronyms)]
@@ -82,7 +95,8 @@ struct IdentifyNamespaceData {
     nows: u16,
     pub mssrl: u16,
     pub mcl: u32,
-    _rsvd1: [u8; 12],
+    pub msrc: u8,
+    _rsvd1: [u8; 11],
     anagrpid: u32,
     _rsvd2: [u8; 3],
     nsattr: u8,
@@ -95,34 +109,529 @@ struct IdentifyNamespaceData {
     vendor_specific: [u8; 3712],
 }
 
+// Identify Controller Data Structure
+// See Figure 275 of the NVMe Base Specification 2.0
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
 #[allow(unused)]
-// Used for the copy command, experimental, only supports 1 source range (TODO)
+struct IdentifyControllerData {
+    vid: u16,
+    ssvid: u16,
+    pub sn: [u8; 20],
+    pub mn: [u8; 40],
+    pub fr: [u8; 8],
+    rab: u8,
+    ieee: [u8; 3],
+    cmic: u8,
+    pub mdts: u8,
+    pub cntlid: u16,
+    ver: u32,
+    rtd3r: u32,
+    rtd3e: u32,
+    pub oaes: u32,
+    pub ctratt: u32,
+    rrls: u16,
+    _rsvd1: [u8; 9],
+    cntrltype: u8,
+    fguid: [u8; 16],
+    crdt: [u16; 3],
+    _rsvd2: [u8; 106],
+    _rsvd_mi: [u8; 16],
+    pub oacs: u16,
+    acl: u8,
+    pub aerl: u8,
+    pub frmw: u8,
+    pub lpa: u8,
+    pub elpe: u8,
+    pub npss: u8,
+    avscc: u8,
+    pub apsta: u8,
+    wctemp: u16,
+    cctemp: u16,
+    mtfa: u16,
+    pub hmpre: u32,
+    pub hmmin: u32,
+    tnvmcap: u128,
+    unvmcap: u128,
+    rpmbs: u32,
+    edstt: u16,
+    dsto: u8,
+    pub fwug: u8,
+    kas: u16,
+    hctma: u16,
+    mntmt: u16,
+    mxtmt: u16,
+    pub sanicap: u32,
+    pub hmminds: u32,
+    pub hmmaxd: u16,
+    nsetidmax: u16,
+    endgidmax: u16,
+    anatt: u8,
+    anacap: u8,
+    anagrpmax: u32,
+    nanagrpid: u32,
+    pels: u32,
+    domainid: u16,
+    _rsvd3: [u8; 10],
+    megcap: u128,
+    _rsvd4: [u8; 128],
+    sqes: u8,
+    cqes: u8,
+    maxcmd: u16,
+    pub nn: u32,
+    pub oncs: u16,
+    pub fuses: u16,
+    fna: u8,
+    vwc: u8,
+    awun: u16,
+    awupf: u16,
+    icsvscc: u8,
+    nwpc: u8,
+    pub acwu: u16,
+    ocfs: u16,
+    sgls: u32,
+    mnan: u32,
+    _rsvd5: [u8; 224],
+    subnqn: [u8; 256],
+    _rsvd6: [u8; 768],
+    _rsvd_of: [u8; 256],
+    pub psd: [[u8; 32]; 32],
+    vendor_specific: [u8; 1024],
+}
+
+// Sanitize Status Log Page
+// See Figure 287 of the NVMe Base Specification 2.0
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+#[allow(unused)]
+struct SanitizeStatusLog {
+    pub sprog: u16,
+    pub sstat: u16,
+    pub scdw10: u32,
+    eto: u32,
+    etbe: u32,
+    etce: u32,
+    etond: u32,
+    etbend: u32,
+    etcend: u32,
+    _rsvd: [u8; 480],
+}
+
+const SANITIZE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+#[allow(unused)]
+// Source Range Entry used by the copy command
+// See Figure 381 of the NVM Command Set Specification
 pub struct SourceRangeEntriesDescriptorFormat0 {
     _reserved1: u64,
     slba: u64,
     n_blocks: u16,
     _reserved2: u16,
-    rest: [u8; 4076]
+    rest: [u8; 12]
+}
+
+// Maximum number of source range entries per copy command, MSRC is a 0's based u8
+const MAX_COPY_RANGES: usize = 256;
+const COPY_RANGE_SIZE: usize = std::mem::size_of::<SourceRangeEntriesDescriptorFormat0>();
+
+// Splits the source ranges, given as (slba, blocks), into the ranges of as few copy commands as
+// MSSRL, MCL and MSRC of the namespace allow
+fn pack_copy_ranges(ns: &NvmeNamespace, ranges: &[(u64, u64)]) -> Vec<Vec<(u64, u64)>> {
+    // NLB of a source range is a 0's based u16, a limit of 0 is treated as unlimited
+    let range_limit = match ns.mssrl {
+        0 => 0x1_0000,
+        mssrl => (mssrl as u64).min(0x1_0000),
+    };
+    let copy_limit = match ns.mcl {
+        0 => u64::MAX,
+        mcl => mcl as u64,
+    };
+    let max_ranges = ns.msrc as usize + 1;
+
+    let mut commands = Vec::new();
+    let mut pending: Vec<(u64, u64)> = Vec::with_capacity(max_ranges);
+    let mut pending_blocks = 0;
+    for &(mut slba, mut blocks) in ranges {
+        while blocks > 0 {
+            let len = blocks.min(range_limit).min(copy_limit - pending_blocks);
+            pending.push((slba, len));
+            pending_blocks += len;
+            slba += len;
+            blocks -= len;
+
+            if pending.len() == max_ranges || pending_blocks == copy_limit {
+                commands.push(std::mem::replace(&mut pending, Vec::with_capacity(max_ranges)));
+                pending_blocks = 0;
+            }
+        }
+    }
+    if !pending.is_empty() {
+        commands.push(pending);
+    }
+    commands
+}
+
+// Writes the source range entries of a copy command to `entries`
+fn write_copy_ranges(entries: *mut SourceRangeEntriesDescriptorFormat0, ranges: &[(u64, u64)]) {
+    assert!(!ranges.is_empty() && ranges.len() <= MAX_COPY_RANGES);
+    for (i, &(slba, blocks)) in ranges.iter().enumerate() {
+        unsafe {
+            *entries.add(i) = SourceRangeEntriesDescriptorFormat0 {
+                _reserved1: 0,
+                slba,
+                n_blocks: (blocks - 1) as u16,
+                _reserved2: 0,
+                rest: [0; 12],
+            };
+        }
+    }
+}
+
+/// When a queue pair writes its doorbell registers, each write is an MMIO access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DoorbellConfig {
+    pub ring_per_command: bool, // Ring the submission doorbell after every command, not once per submission call
+    pub cq_coalesce: usize, // Completions consumed before the completion doorbell is written, 1 writes it on every poll
+}
+
+impl Default for DoorbellConfig {
+    fn default() -> Self {
+        Self {
+            ring_per_command: false,
+            cq_coalesce: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DoorbellStats {
+    pub sq_writes: u64,
+    pub cq_writes: u64,
+    pub commands: u64,
+    pub completions: u64,
+}
+
+impl DoorbellStats {
+    pub fn commands_per_sq_write(&self) -> f64 {
+        if self.sq_writes == 0 {
+            return 0.0;
+        }
+        self.commands as f64 / self.sq_writes as f64
+    }
+
+    pub fn completions_per_cq_write(&self) -> f64 {
+        if self.cq_writes == 0 {
+            return 0.0;
+        }
+        self.completions as f64 / self.cq_writes as f64
+    }
+}
+
+// Doorbell values not yet written to the controller
+#[derive(Default)]
+struct Doorbells {
+    config: DoorbellConfig,
+    stats: DoorbellStats,
+    sq_tail: usize, // Last written submission queue tail
+    cq_head: usize, // Head after the last consumed completion
+    cq_pending: usize, // Consumed completions the controller wasn't told about yet
+}
+
+/// What a queue pair does when its submission queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SubmitMode {
+    /// Stop and return the part that was submitted, see `Submission`
+    #[default]
+    Partial,
+    /// Reap completions until there is room again, giving up with a partial submission after the timeout
+    Blocking(Duration),
+}
+
+/// Outcome of a submission that may be split into several commands.
+/// Unless `is_complete`, the submission queue filled up and the rest can be submitted from the resume point:
+/// `bytes` into the buffer, at `next_lba`.
+#[must_use = "a partial submission has to be resumed or its remaining data is never transferred"]
+#[derive(Debug, Clone, Default)]
+pub struct Submission {
+    pub commands: usize,
+    pub bytes: usize,
+    pub blocks: u64,
+    pub next_lba: u64, // For appends the zone start, for copies the next source block
+    pub remaining_bytes: usize, // Not submitted, 0 if complete
+    // Completions taken while waiting for room in blocking mode, of this or of earlier submissions.
+    // They don't need completing anymore, their command ids tell which commands they belong to.
+    pub reaped: Vec<NvmeCompletion>,
+    pub timed_out: bool, // Blocking mode gave up waiting for room
+}
+
+impl Submission {
+    fn new(next_lba: u64, remaining_bytes: usize) -> Self {
+        Self {
+            next_lba,
+            remaining_bytes,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.remaining_bytes == 0
+    }
+
+    fn advance(&mut self, bytes: usize, blocks: u64) {
+        self.bytes += bytes;
+        self.blocks += blocks;
+        self.remaining_bytes -= bytes;
+    }
+
+    /// Turns a partial submission into an error, for callers that can't resume it
+    pub fn ensure_complete(self) -> Result<Self, Box<dyn Error>> {
+        if !self.is_complete() {
+            let total = self.bytes + self.remaining_bytes;
+            return Err(format!("queue full, {} of {total} bytes not submitted", self.remaining_bytes).into());
+        }
+        Ok(self)
+    }
+
+    /// Commands of this submission still to be completed with `complete_io`, assuming nothing else was outstanding
+    pub fn outstanding(&self) -> usize {
+        self.commands.saturating_sub(self.reaped.len())
+    }
 }
 
 pub struct NvmeQueuePair {
     pub id: u16,
     pub sub_queue: NvmeSubQueue,
     comp_queue: NvmeCompQueue,
+    submit_mode: SubmitMode,
+    doorbells: Doorbells,
+    prp_lists: [Option<Dma<u64>>; 2], // Of non-contiguous completion and submission queues, used by the controller until deleted
+    acwu: Option<u64>, // Atomic compare and write unit in blocks, None if fused compare and write is unsupported
+    copy_supported: bool, // ONCS bit 8
+    reservations_supported: bool, // ONCS bit 5
+    extended_host_id: bool, // Controller supports 128 bit host identifiers in reservation reports
+}
+
+// Builds a fused Compare + Write pair, both buffers need to fit into a single PRP pair (8KiB).
+// Command ids are left to the caller, each one has to match the submission queue slot it is written to.
+fn fused_compare_write(
+    ns_id: u32,
+    block_size: u64,
+    compare: &impl DmaSlice,
+    data: &impl DmaSlice,
+    lba: u64,
+    acwu: u64,
+) -> Result<(NvmeCommand, NvmeCommand), Box<dyn Error>> {
+    let mut compare_chunks = compare.chunks(2 * 4096);
+    let mut data_chunks = data.chunks(2 * 4096);
+    let (cmp, write) = match (compare_chunks.next(), data_chunks.next()) {
+        (Some(cmp), Some(write)) => (cmp, write),
+        _ => return Err("Compare and Write needs a non-empty buffer".into()),
+    };
+    if compare_chunks.next().is_some() || data_chunks.next().is_some() {
+        return Err("Compare and Write buffers larger than 8KiB are not supported".into());
+    }
+    if cmp.slice.len() != write.slice.len() {
+        return Err("Compare and Write buffers differ in length".into());
+    }
+
+    let blocks = (cmp.slice.len() as u64).div_ceil(block_size);
+    if blocks > acwu {
+        return Err(format!("Compare and Write of {blocks} blocks exceeds ACWU of {acwu} blocks").into());
+    }
+
+    let prp2 = |addr: u64| if blocks * block_size <= 4096 { 0 } else { addr + 4096 };
+    let cmp_addr = cmp.phys_addr as u64;
+    let write_addr = write.phys_addr as u64;
+    let compare = NvmeCommand::io_compare(0, ns_id, lba, blocks as u16 - 1, cmp_addr, prp2(cmp_addr)).fused(false);
+    let write = NvmeCommand::io_write(0, ns_id, lba, blocks as u16 - 1, write_addr, prp2(write_addr)).fused(true);
+    Ok((compare, write))
 }
 
+// Evaluates the completions of a fused Compare + Write pair, returns false on a miscompare
+fn fused_compare_write_status(completions: [NvmeCompletion; 2]) -> Result<bool, Box<dyn Error>> {
+    let mut compare_failed = false;
+    for c_entry in completions {
+        let status = c_entry.status >> 1;
+        let (sc, sct) = (status & 0xFF, (status >> 8) & 0x7);
+        match (sct, sc) {
+            (0, 0) => {}
+            // Compare Failure
+            (2, 0x85) => compare_failed = true,
+            // Command Aborted due to Failed Fused Command
+            (0, 0x9) => {}
+            _ => {
+                eprintln!(
+                    "Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
+                    status, sc, sct
+                );
+                return Err("Compare and Write failed".into());
+            }
+        }
+    }
+    Ok(!compare_failed)
+}
+
+// Maps a failed I/O completion to an error, reservation conflicts get their own error type
+fn io_error(c_entry: NvmeCompletion, msg: &str) -> Box<dyn Error> {
+    if is_reservation_conflict(c_entry.status) {
+        Box::new(ReservationConflict)
+    } else {
+        msg.into()
+    }
+}
+
+// Change Persist Through Power Loss State field of Reservation Register
+fn reservation_cptpl(ptpl: Option<bool>) -> u8 {
+    match ptpl {
+        None => 0,
+        Some(false) => 2,
+        Some(true) => 3,
+    }
+}
+
+// Size of the Reservation Status data structure read by reservation_report, fits 63 (extended) registrants
+const RESERVATION_REPORT_SIZE: usize = 4096;
+
 unsafe impl Send for NvmeQueuePair {}
 unsafe impl Sync for NvmeQueuePair {}
 
 impl NvmeQueuePair {
-    /// returns amount of requests pushed into submission queue
-    pub fn submit_io(&mut self, ns_id: u32, block_size: u64, data: &impl DmaSlice, mut lba: u64, write: bool) -> usize {
-        let mut reqs = 0;
+    /// Submits a read or write of `data` at `lba`, split into commands of up to 8KiB.
+    /// If the submission queue fills up, the result tells how far it got, see `SubmitMode`.
+    pub fn submit_io(&mut self, ns_id: u32, block_size: u64, data: &impl DmaSlice, lba: u64, write: bool) -> Submission {
+        self.submit_io_stream(ns_id, block_size, data, lba, write, None)
+    }
+
+    /// Like `submit_io`, tagging writes with `stream_id` if set
+    pub fn submit_io_stream(
+        &mut self,
+        ns_id: u32,
+        block_size: u64,
+        data: &impl DmaSlice,
+        lba: u64,
+        write: bool,
+        stream_id: Option<u16>,
+    ) -> Submission {
+        let directive = stream_id.map(|id| (DTYPE_STREAMS, id));
+        let submission = self.prepare_io_directive(ns_id, block_size, data, lba, write, directive);
+        self.ring();
+        submission
+    }
+
+    /// Like `submit_io`, placing writes with the FDP placement identifier `placement_id` if set
+    pub fn submit_io_placement(
+        &mut self,
+        ns_id: u32,
+        block_size: u64,
+        data: &impl DmaSlice,
+        lba: u64,
+        write: bool,
+        placement_id: Option<u16>,
+    ) -> Submission {
+        let directive = placement_id.map(|id| (DTYPE_DATA_PLACEMENT, id));
+        let submission = self.prepare_io_directive(ns_id, block_size, data, lba, write, directive);
+        self.ring();
+        submission
+    }
+
+    /// Like `submit_io`, but leaves ringing the doorbell to `ring`, so several submissions cost a single doorbell write.
+    /// Blocking mode rings before it waits for room.
+    pub fn prepare_io(&mut self, ns_id: u32, block_size: u64, data: &impl DmaSlice, lba: u64, write: bool) -> Submission {
+        self.prepare_io_directive(ns_id, block_size, data, lba, write, None)
+    }
+
+    /// Queues the command built by `cmd_init` from its command id without ringing the doorbell, see `ring`
+    pub fn prepare<F: FnOnce(u16) -> NvmeCommand>(&mut self, cmd_init: F) -> Result<u16, Box<dyn Error>> {
+        let c_id = self.sub_queue.c_id(self.id);
+        self.sub_queue.submit_checked(cmd_init(c_id)).ok_or("queue full")?;
+        self.queued();
+        Ok(c_id)
+    }
+
+    /// Writes the submission doorbell if commands were queued since the last write, returns whether it did
+    pub fn ring(&mut self) -> bool {
+        if self.sub_queue.tail == self.doorbells.sq_tail {
+            return false;
+        }
+        // Consumed completions are released first, so the controller always has room to post the new ones
+        self.ring_cq();
+        unsafe {
+            std::ptr::write_volatile(self.sub_queue.doorbell as *mut u32, self.sub_queue.tail as u32);
+        }
+        self.doorbells.sq_tail = self.sub_queue.tail;
+        self.doorbells.stats.sq_writes += 1;
+        true
+    }
+
+    // Writes the completion doorbell if completions were consumed since the last write
+    fn ring_cq(&mut self) {
+        if self.doorbells.cq_pending == 0 {
+            return;
+        }
+        unsafe {
+            std::ptr::write_volatile(self.comp_queue.doorbell as *mut u32, self.doorbells.cq_head as u32);
+        }
+        self.doorbells.cq_pending = 0;
+        self.doorbells.stats.cq_writes += 1;
+    }
+
+    // Counts a command written to the submission queue
+    fn queued(&mut self) {
+        self.doorbells.stats.commands += 1;
+        if self.doorbells.config.ring_per_command {
+            self.ring();
+        }
+    }
+
+    // Counts `n` consumed completions up to `head`, written to the doorbell once enough accumulated
+    fn consumed(&mut self, head: usize, n: usize) {
+        self.doorbells.cq_head = head;
+        self.doorbells.cq_pending += n;
+        self.doorbells.stats.completions += n as u64;
+        if self.doorbells.cq_pending >= self.doorbells.config.cq_coalesce {
+            self.ring_cq();
+        }
+    }
+
+    pub fn set_doorbell_config(&mut self, config: DoorbellConfig) {
+        self.doorbells.config = config;
+        if config.ring_per_command {
+            self.ring();
+        }
+        if self.doorbells.cq_pending >= config.cq_coalesce {
+            self.ring_cq();
+        }
+    }
+
+    pub fn doorbell_config(&self) -> DoorbellConfig {
+        self.doorbells.config
+    }
+
+    /// Doorbell writes against the commands and completions they covered
+    pub fn doorbell_stats(&self) -> DoorbellStats {
+        self.doorbells.stats
+    }
+
+    pub fn reset_doorbell_stats(&mut self) {
+        self.doorbells.stats = DoorbellStats::default();
+    }
+
+    // directive is the directive type and directive specific value of writes
+    fn prepare_io_directive(
+        &mut self,
+        ns_id: u32,
+        block_size: u64,
+        data: &impl DmaSlice,
+        lba: u64,
+        write: bool,
+        directive: Option<(u8, u16)>,
+    ) -> Submission {
+        let mut submission = Submission::new(lba, dma_len(data));
         // TODO: contruct PRP list?
         for chunk in data.chunks(2 * 4096) {
-            let blocks = (chunk.slice.len() as u64 + block_size - 1) / block_size; // No??
+            let blocks = (chunk.slice.len() as u64).div_ceil(block_size);
 
             let addr = chunk.phys_addr as u64;
             let bytes = blocks * block_size;
@@ -132,45 +641,31 @@ impl NvmeQueuePair {
                 addr + 4096 // self.page_size
             };
 
+            let c_id = self.sub_queue.c_id(self.id);
             let entry = if write {
-                NvmeCommand::io_write(
-                    self.id << 11 | self.sub_queue.tail as u16,
-                    ns_id,
-                    lba,
-                    blocks as u16 - 1,
-                    addr,
-                    ptr1,
-                )
+                let entry = NvmeCommand::io_write(c_id, ns_id, submission.next_lba, blocks as u16 - 1, addr, ptr1);
+                match directive {
+                    Some((dtype, dspec)) => entry.directive(dtype, dspec),
+                    None => entry,
+                }
             } else {
-                NvmeCommand::io_read(
-                    self.id << 11 | self.sub_queue.tail as u16,
-                    ns_id,
-                    lba,
-                    blocks as u16 - 1,
-                    addr,
-                    ptr1,
-                )
+                NvmeCommand::io_read(c_id, ns_id, submission.next_lba, blocks as u16 - 1, addr, ptr1)
             };
 
-            if let Some(tail) = self.sub_queue.submit_checked(entry) {
-                unsafe {
-                    std::ptr::write_volatile(self.sub_queue.doorbell as *mut u32, tail as u32);
-                }
-            } else {
-                eprintln!("queue full");
-                return reqs;
+            if !self.submit_queued(entry, &mut submission) {
+                return submission;
             }
-
-            lba += blocks;
-            reqs += 1;
+            submission.advance(chunk.slice.len(), blocks);
+            submission.next_lba += blocks;
         }
-        reqs
+        submission
     }
 
-    pub fn append_io(&mut self, ns_id: u32, block_size: u64, data: &impl DmaSlice, zslba: u64) -> usize {
-        let mut reqs = 0;
+    /// Appends `data` to the zone starting at `zslba`, split into commands of up to 8KiB like `submit_io`
+    pub fn append_io(&mut self, ns_id: u32, block_size: u64, data: &impl DmaSlice, zslba: u64) -> Submission {
+        let mut submission = Submission::new(zslba, dma_len(data));
         for chunk in data.chunks(2 * 4096) {
-            let blocks = (chunk.slice.len() as u64 + block_size - 1) / block_size;
+            let blocks = (chunk.slice.len() as u64).div_ceil(block_size);
 
             let addr = chunk.phys_addr as u64;
             let bytes = blocks * block_size;
@@ -180,88 +675,345 @@ impl NvmeQueuePair {
                 addr + 4096 // self.page_size
             };
             let entry = NvmeCommand::zone_append(
-                self.id << 11 | self.sub_queue.tail as u16,
-                ns_id, 
-                zslba, 
-                blocks as u16 - 1, 
-                addr, 
-                ptr1);
-            
-            if let Some(tail) = self.sub_queue.submit_checked(entry) {
-                unsafe {
-                    std::ptr::write_volatile(self.sub_queue.doorbell as *mut u32, tail as u32);
-                }
-            } else {
-                eprintln!("queue full");
-                return reqs;
+                self.sub_queue.c_id(self.id),
+                ns_id,
+                zslba,
+                blocks as u16 - 1,
+                addr,
+                ptr1,
+            );
+
+            if !self.submit_queued(entry, &mut submission) {
+                break;
+            }
+            submission.advance(chunk.slice.len(), blocks);
+        }
+        self.ring();
+        submission
+    }
+
+    /// Copies `len` blocks from `src` to `dest` in namespace `ns`, split into commands like `NvmeDevice::copy_ranges`.
+    /// Every command takes its source range entries from its own part of `buffer`, which must not be reused
+    /// until the commands completed. The submission stops early if `buffer` is used up.
+    /// `bytes` and `remaining_bytes` of the result count the copied data, `next_lba` is the next source block.
+    pub fn copy(
+        &mut self,
+        ns: &NvmeNamespace,
+        src: u64,
+        mut dest: u64,
+        len: u64,
+        buffer: &mut Dma<u8>,
+    ) -> Result<Submission, Box<dyn Error>> {
+        if !self.copy_supported {
+            return Err("Copy command is not supported by the controller".into());
+        }
+        let mut submission = Submission::new(src, (len * ns.block_size) as usize);
+        let mut offset = 0;
+        for ranges in pack_copy_ranges(ns, &[(src, len)]) {
+            // Entries of up to a page stay within one, larger ones start on a page and take the next one as PRP2
+            let entries_size = ranges.len() * COPY_RANGE_SIZE;
+            if offset % 4096 + entries_size > 4096 {
+                offset = offset.next_multiple_of(4096);
             }
-            reqs += 1;
-        }
-        reqs
-    }
-
-    pub fn copy(&mut self, ns_id: u32, mut src: u64, mut dest: u64, mut len: u64, buffer: &mut Dma<u8>) -> usize {
-        assert!(buffer.size >= 4096);
-        let mut reqs = 0;
-        while len > 0 {
-            let current_len = std::cmp::min(len, 128);
-            let data = buffer.virt as *mut SourceRangeEntriesDescriptorFormat0;
-            unsafe {
-                (*data).slba = src;
-                (*data).n_blocks = current_len as u16 - 1;
-                (*data)._reserved1 = 0;
-                (*data)._reserved2 = 0;
-                (*data).rest = [0; 4076];
+            if offset + entries_size > buffer.size {
+                break;
             }
-            let ptr0 = buffer.phys as u64;
+            write_copy_ranges(unsafe { buffer.virt.add(offset) } as *mut SourceRangeEntriesDescriptorFormat0, &ranges);
+            let ptr0 = buffer.phys as u64 + offset as u64;
+            let ptr1 = if entries_size > 4096 { ptr0 + 4096 } else { 0 };
+            let blocks: u64 = ranges.iter().map(|&(_, blocks)| blocks).sum();
 
             let entry = NvmeCommand::copy(
-                self.id << 11 | self.sub_queue.tail as u16,
-                ns_id,
+                self.sub_queue.c_id(self.id),
+                ns.id,
                 dest,
+                (ranges.len() - 1) as u8,
                 ptr0,
+                ptr1,
             );
 
-            if let Some(tail) = self.sub_queue.submit_checked(entry) {
-                unsafe {
-                    std::ptr::write_volatile(self.sub_queue.doorbell as *mut u32, tail as u32);
+            if !self.submit_queued(entry, &mut submission) {
+                break;
+            }
+            submission.advance((blocks * ns.block_size) as usize, blocks);
+            submission.next_lba += blocks;
+            dest += blocks;
+            offset += entries_size;
+        }
+        self.ring();
+        Ok(submission)
+    }
+
+    /// Sets what submissions do when the submission queue is full
+    pub fn set_submit_mode(&mut self, mode: SubmitMode) {
+        self.submit_mode = mode;
+    }
+
+    pub fn submit_mode(&self) -> SubmitMode {
+        self.submit_mode
+    }
+
+    // Queues one command of a multi-command submission, the caller rings the doorbell.
+    // In blocking mode waits for room by reaping completions, otherwise returns false on a full queue.
+    fn submit_queued(&mut self, entry: NvmeCommand, submission: &mut Submission) -> bool {
+        if let SubmitMode::Blocking(timeout) = self.submit_mode {
+            if self.sub_queue.is_full() {
+                // The controller has to see the queued commands to complete them
+                self.ring();
+                let start = Instant::now();
+                while self.sub_queue.is_full() {
+                    match self.poll_completion() {
+                        Some(c_entry) => submission.reaped.push(c_entry),
+                        None if start.elapsed() >= timeout => {
+                            submission.timed_out = true;
+                            return false;
+                        }
+                        None => spin_loop(),
+                    }
                 }
-            } else {
-                eprintln!("queue full");
-                return reqs;
             }
+        }
+        if self.sub_queue.submit_checked(entry).is_none() {
+            return false;
+        }
+        self.queued();
+        submission.commands += 1;
+        true
+    }
 
-            len -= current_len;
-            src += current_len;
-            dest += current_len;
-            reqs += 1;
+    /// Atomically compares `compare` with the blocks at `lba` and writes `data` if they match.
+    /// Both commands are submitted as a fused pair with a single doorbell write and completed before returning,
+    /// so this should not be mixed with other outstanding I/O on the queue pair.
+    /// Returns `Ok(false)` if the compare failed and the write was aborted.
+    pub fn compare_and_write(
+        &mut self,
+        ns_id: u32,
+        block_size: u64,
+        compare: &impl DmaSlice,
+        data: &impl DmaSlice,
+        lba: u64,
+    ) -> Result<bool, Box<dyn Error>> {
+        let acwu = self.acwu.ok_or("Compare and Write is not supported by the controller")?;
+        if self.sub_queue.free_slots() < 2 {
+            return Err("queue full".into());
         }
+        let (mut compare, mut write) = fused_compare_write(ns_id, block_size, compare, data, lba, acwu)?;
+
+        // Fused commands have to be adjacent in the queue, the doorbell is only rung after the second one
+        compare.c_id = self.sub_queue.c_id(self.id);
+        self.sub_queue.submit(compare);
+        write.c_id = self.sub_queue.c_id(self.id);
+        self.sub_queue.submit(write);
+        self.doorbells.stats.commands += 2;
+        self.ring();
+
+        let (_, first, _) = self.comp_queue.complete_spin();
+        let (head, second, _) = self.comp_queue.complete_spin();
+        self.consumed(head, 2);
+        self.sub_queue.head = second.sq_head as usize;
+        fused_compare_write_status([first, second])
+    }
 
-        reqs
+    /// Registers, unregisters or replaces the reservation key of this host on namespace `ns_id`.
+    /// `ptpl` sets whether reservations persist through power loss, `None` keeps the current setting.
+    /// Like `compare_and_write`, the command is completed before returning.
+    #[allow(clippy::too_many_arguments)]
+    pub fn reservation_register(
+        &mut self,
+        ns_id: u32,
+        action: ReservationRegisterAction,
+        current_key: u64,
+        new_key: u64,
+        ignore_key: bool,
+        ptpl: Option<bool>,
+        buffer: &mut Dma<u8>,
+    ) -> Result<(), Box<dyn Error>> {
+        buffer[..8].copy_from_slice(&current_key.to_le_bytes());
+        buffer[8..16].copy_from_slice(&new_key.to_le_bytes());
+        let cptpl = reservation_cptpl(ptpl);
+        self.reservation_io(buffer, |c_id, ptr| {
+            NvmeCommand::reservation_register(c_id, ns_id, ptr, action as u8, ignore_key, cptpl)
+        })
     }
 
-    pub fn zone_action(&mut self, ns_id: u32, zslba: u64, za: ZnsZsa) {
-		let entry = NvmeCommand::zone_management_send(
-            self.id << 11 | self.sub_queue.tail as u16,
-            ns_id, 
-            zslba, 
-            false, 
-            za as u8,
-            0);        
-        let tail = self.sub_queue.submit_checked(entry).unwrap();
-        unsafe {
-            std::ptr::write_volatile(self.sub_queue.doorbell as *mut u32, tail as u32);
+    /// Acquires a reservation of type `rtype` with `key`, or preempts the registrant holding `preempt_key`
+    pub fn reservation_acquire(
+        &mut self,
+        ns_id: u32,
+        action: ReservationAcquireAction,
+        rtype: ReservationType,
+        key: u64,
+        preempt_key: u64,
+        buffer: &mut Dma<u8>,
+    ) -> Result<(), Box<dyn Error>> {
+        buffer[..8].copy_from_slice(&key.to_le_bytes());
+        buffer[8..16].copy_from_slice(&preempt_key.to_le_bytes());
+        self.reservation_io(buffer, |c_id, ptr| {
+            NvmeCommand::reservation_acquire(c_id, ns_id, ptr, action as u8, false, rtype as u8)
+        })
+    }
+
+    /// Releases the reservation of type `rtype` held with `key`, or clears all reservations and registrations
+    pub fn reservation_release(
+        &mut self,
+        ns_id: u32,
+        action: ReservationReleaseAction,
+        rtype: ReservationType,
+        key: u64,
+        buffer: &mut Dma<u8>,
+    ) -> Result<(), Box<dyn Error>> {
+        buffer[..8].copy_from_slice(&key.to_le_bytes());
+        self.reservation_io(buffer, |c_id, ptr| {
+            NvmeCommand::reservation_release(c_id, ns_id, ptr, action as u8, false, rtype as u8)
+        })
+    }
+
+    /// Returns the reservation holder and the registered hosts of namespace `ns_id`
+    pub fn reservation_report(&mut self, ns_id: u32, buffer: &mut Dma<u8>) -> Result<ReservationStatus, Box<dyn Error>> {
+        if buffer.size < RESERVATION_REPORT_SIZE {
+            return Err("Reservation report buffer too small".into());
+        }
+        let numd = (RESERVATION_REPORT_SIZE / 4 - 1) as u32;
+        let extended = self.extended_host_id;
+        self.reservation_io(buffer, |c_id, ptr| {
+            NvmeCommand::reservation_report(c_id, ns_id, ptr, 0, numd, extended)
+        })?;
+        Ok(ReservationStatus::parse(&buffer[..RESERVATION_REPORT_SIZE], extended))
+    }
+
+    fn reservation_io<F: FnOnce(u16, u64) -> NvmeCommand>(
+        &mut self,
+        buffer: &Dma<u8>,
+        cmd_init: F,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self.reservations_supported {
+            return Err("Reservations are not supported by the controller".into());
+        }
+        let entry = cmd_init(self.sub_queue.c_id(self.id), buffer.phys as u64);
+        self.sub_queue.submit_checked(entry).ok_or("queue full")?;
+        self.doorbells.stats.commands += 1;
+        self.ring();
+
+        let (head, c_entry, _) = self.comp_queue.complete_spin();
+        self.consumed(head, 1);
+        self.sub_queue.head = c_entry.sq_head as usize;
+        if c_entry.status >> 1 != 0 {
+            return Err(io_error(c_entry, "Reservation command failed"));
+        }
+        Ok(())
+    }
+
+    /// Starts recording every submitted command and received completion into a ring of the last `capacity` records.
+    /// The returned ring can be inspected or dumped from another thread while the queue pair is in use.
+    pub fn enable_trace(&mut self, capacity: usize) -> SharedTrace {
+        let trace = TraceRing::shared(self.id, capacity);
+        self.sub_queue.trace = Some(trace.clone());
+        self.comp_queue.trace = Some(trace.clone());
+        trace
+    }
+
+    /// Stops recording, returns the ring if tracing was enabled
+    pub fn disable_trace(&mut self) -> Option<SharedTrace> {
+        self.comp_queue.trace = None;
+        self.sub_queue.trace.take()
+    }
+
+    /// Starts collecting statistics in `registry`, see `NvmeDevice::stats_registry`.
+    /// They cost a lock and a clock read per command, so they're off by default.
+    pub fn enable_stats(&mut self, registry: &StatsRegistry) {
+        let stats = registry.register(self.id, self.sub_queue.len());
+        self.sub_queue.stats = Some(stats.clone());
+        self.comp_queue.stats = Some(stats);
+    }
+
+    /// Stops collecting statistics, the registry keeps the last values until the queue pair is deleted
+    pub fn disable_stats(&mut self) {
+        self.sub_queue.stats = None;
+        self.comp_queue.stats = None;
+    }
+
+    /// Snapshot of the I/O statistics of this queue pair over all namespaces, empty unless enabled
+    pub fn stats(&self) -> IoStats {
+        self.sub_queue
+            .stats
+            .as_ref()
+            .map(|stats| stats.lock().unwrap().total().clone())
+            .unwrap_or_default()
+    }
+
+    /// Snapshot of the I/O statistics of this queue pair for namespace `ns_id`
+    pub fn namespace_stats(&self, ns_id: u32) -> Option<IoStats> {
+        let stats = self.sub_queue.stats.as_ref()?.lock().unwrap();
+        stats.namespace(ns_id).cloned()
+    }
+
+    pub fn reset_stats(&self) {
+        if let Some(stats) = &self.sub_queue.stats {
+            stats.lock().unwrap().reset();
+        }
+    }
+
+    // Submits a prepared command and rings the doorbell, None if the submission queue is full
+    pub(crate) fn submit_command(&mut self, entry: NvmeCommand) -> Option<()> {
+        self.sub_queue.submit_checked(entry)?;
+        self.doorbells.stats.commands += 1;
+        self.ring();
+        Some(())
+    }
+
+    // Takes the next completion entry if one arrived, without interpreting its status
+    pub(crate) fn poll_completion(&mut self) -> Option<NvmeCompletion> {
+        let (head, c_entry, _) = self.comp_queue.complete()?;
+        self.consumed(head, 1);
+        self.sub_queue.head = c_entry.sq_head as usize;
+        Some(c_entry)
+    }
+
+    // Takes up to `max` arrived completion entries, the completion doorbell is written at most once for all of them
+    pub(crate) fn poll_completions<F: FnMut(NvmeCompletion)>(&mut self, max: usize, mut f: F) -> usize {
+        let mut n = 0;
+        let mut head = None;
+        while n < max {
+            let Some((next, c_entry, _)) = self.comp_queue.complete() else {
+                break;
+            };
+            head = Some(next);
+            self.sub_queue.head = c_entry.sq_head as usize;
+            f(c_entry);
+            n += 1;
         }
+        if let Some(head) = head {
+            self.consumed(head, n);
+        }
+        n
     }
 
+    /// Submits a zone management send action on the zone starting at `zslba`.
+    /// Fails on a full submission queue unless the queue pair is in blocking mode, where it reaps completions first.
+    pub fn zone_action(&mut self, ns_id: u32, zslba: u64, za: ZnsZsa) -> Result<Submission, Box<dyn Error>> {
+        let entry = NvmeCommand::zone_management_send(
+            self.sub_queue.c_id(self.id),
+            ns_id,
+            zslba,
+            false,
+            za as u8,
+            0,
+        );
+        let mut submission = Submission::new(zslba, 0);
+        if !self.submit_queued(entry, &mut submission) {
+            return Err("queue full".into());
+        }
+        self.ring();
+        Ok(submission)
+    }
 
     // TODO: maybe return result
     pub fn complete_io(&mut self, n: usize) -> Option<u16> {
         assert!(n > 0);
-        let (tail, c_entry, _) = self.comp_queue.complete_n(n);
-        unsafe {
-            std::ptr::write_volatile(self.comp_queue.doorbell as *mut u32, tail as u32);
-        }
+        // Prepared commands would never complete otherwise
+        self.ring();
+        let (head, c_entry, _) = self.comp_queue.complete_n(n);
+        self.consumed(head, n);
         self.sub_queue.head = c_entry.sq_head as usize;
         let status = c_entry.status >> 1;
         if status != 0 {
@@ -278,10 +1030,8 @@ impl NvmeQueuePair {
     }
 
     pub fn quick_poll(&mut self) -> Option<()> {
-        if let Some((tail, c_entry, _)) = self.comp_queue.complete() {
-            unsafe {
-                std::ptr::write_volatile(self.comp_queue.doorbell as *mut u32, tail as u32);
-            }
+        if let Some((head, c_entry, _)) = self.comp_queue.complete() {
+            self.consumed(head, 1);
             self.sub_queue.head = c_entry.sq_head as usize;
             let status = c_entry.status >> 1;
             if status != 0 {
@@ -299,28 +1049,109 @@ impl NvmeQueuePair {
     }
 }
 
-#[allow(unused)]
-pub struct NvmeDevice {
-    pci_addr: String,
-    addr: *mut u8,
-    len: usize,
-    // Doorbell stride
-    dstrd: u16,
-    admin_sq: NvmeSubQueue,
-    admin_cq: NvmeCompQueue,
-    io_sq: NvmeSubQueue,
-    io_cq: NvmeCompQueue,
-    buffer: Dma<u8>,           // 2MiB of buffer
+// Where the controller finds a queue: its first page if the queue is physically contiguous, otherwise a PRP list of its pages
+struct QueueMemory {
+    addr: usize,
+    prp_list: Option<Dma<u64>>,
+}
+
+impl QueueMemory {
+    fn locate(pages: Vec<u64>, contiguous_required: bool) -> Result<Self, Box<dyn Error>> {
+        let contiguous = pages.windows(2).all(|w| w[1] == w[0] + 4096);
+        if contiguous {
+            return Ok(Self {
+                addr: pages[0] as usize,
+                prp_list: None,
+            });
+        }
+        if contiguous_required {
+            return Err("Queue memory is not physically contiguous and the controller requires contiguous queues".into());
+        }
+        // A huge page holds the PRP entries of far more than the largest queue
+        let prp_list: Dma<u64> = Dma::allocate(pages.len() * 8)?;
+        for (i, &page) in pages.iter().enumerate() {
+            unsafe { prp_list.virt.add(i).write(page) };
+        }
+        Ok(Self {
+            addr: prp_list.phys,
+            prp_list: Some(prp_list),
+        })
+    }
+
+    fn contiguous(&self) -> bool {
+        self.prp_list.is_none()
+    }
+}
+
+// Bytes covered by a DMA slice
+fn dma_len(data: &impl DmaSlice) -> usize {
+    data.chunks(2 * 4096).map(|chunk| chunk.slice.len()).sum()
+}
+
+#[allow(unused)]
+pub struct NvmeDevice {
+    pci_addr: String,
+    addr: *mut u8,
+    len: usize,
+    // Doorbell stride
+    dstrd: u16,
+    admin_sq: NvmeSubQueue,
+    admin_cq: NvmeCompQueue,
+    io_sq: NvmeSubQueue,
+    io_cq: NvmeCompQueue,
+    buffer: Dma<u8>,           // 2MiB of buffer
     prp_list: Dma<[u64; 512]>, // Address of PRP's, devices doesn't necessarily support 2MiB page sizes; 8 Bytes * 512 = 4096
     pub namespaces: HashMap<u32, NvmeNamespace>,
-    pub stats: NvmeStats,
+    pub stats: NvmeStats, // Commands of the device's own I/O queue, detailed statistics are opt-in, see `enable_stats`
+    stats_registry: StatsRegistry,
     q_id: u16,
+    ctrl: IdentifyControllerData,
+    aer_outstanding: BTreeSet<u16>, // Command ids of outstanding Asynchronous Event Requests
+    aer_pending: Vec<NvmeCompletion>,
+    event_handlers: Vec<AsyncEventHandler>,
+    hmb: Option<HostMemoryBuffer>,
+}
+
+// Host memory granted to the controller, it must stay allocated until the buffer is disabled
+struct HostMemoryBuffer {
+    chunks: Vec<Dma<u8>>,
+    size: usize, // Granted to the controller, the last chunk may be used only partially
+    descriptors: Dma<[HostMemoryBufferDescriptor; HMB_MAX_DESCRIPTORS]>,
+}
+
+// Host Memory Buffer Descriptor Entry
+// See Figure 340 of the NVMe Base Specification 2.0
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct HostMemoryBufferDescriptor {
+    badd: u64, // Buffer address, memory page aligned
+    bsize: u32, // Buffer size in memory pages
+    _rsvd: u32,
 }
 
+// Descriptors fitting into the single page of the descriptor list
+const HMB_MAX_DESCRIPTORS: usize = 4096 / std::mem::size_of::<HostMemoryBufferDescriptor>();
+// Disabling the host memory buffer also runs on drop, where a hung controller shouldn't block forever
+const HMB_DISABLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+type AsyncEventHandler = Box<dyn FnMut(&AsyncEvent) + Send>;
+
+// Set in the command id of Asynchronous Event Requests, they stay outstanding while the admin queue wraps around
+const AER_CID_FLAG: u16 = 0x8000;
+
 // TODO
 unsafe impl Send for NvmeDevice {}
 unsafe impl Sync for NvmeDevice {}
 
+impl Drop for NvmeDevice {
+    fn drop(&mut self) {
+        // The controller may keep accessing the host memory buffer until it is disabled
+        if let Err(e) = self.disable_host_memory_buffer() {
+            eprintln!("Disabling the host memory buffer failed: {e}");
+        }
+    }
+}
+
 #[allow(unused)]
 impl NvmeDevice {
     pub fn init(pci_addr: &str) -> Result<Self, Box<dyn Error>> {
@@ -345,7 +1176,13 @@ impl NvmeDevice {
             prp_list: Dma::allocate(8 * 512)?,
             namespaces: HashMap::new(),
             stats: NvmeStats::default(),
+            stats_registry: StatsRegistry::default(),
             q_id: 1,
+            ctrl: unsafe { std::mem::zeroed() },
+            aer_outstanding: BTreeSet::new(),
+            aer_pending: Vec::new(),
+            event_handlers: Vec::new(),
+            hmb: None,
         };
 
         for i in 1..512 {
@@ -396,6 +1233,11 @@ impl NvmeDevice {
             cc |= (6 << 4); // 110b
         }
 
+        // CAP.AMS bit 17: weighted round robin with urgent priority class, selected with CC.AMS = 001b
+        if (dev.get_reg64(NvmeRegs64::CAP as u64) >> 17) & 1 == 1 {
+            cc |= 1 << 11;
+        }
+
         // Set Memory Page Size
         // let mpsmax = ((dev.get_reg64(NvmeRegs64::CAP as u64) >> 52) & 0xF) as u32;
         // cc |= (mpsmax << 7);
@@ -423,7 +1265,7 @@ impl NvmeDevice {
         let addr = dev.io_cq.get_addr();
         println!("Requesting i/o completion queue");
         let comp = dev.submit_and_complete_admin(|c_id, _| {
-            NvmeCommand::create_io_completion_queue(c_id, q_id, addr, (QUEUE_LENGTH - 1) as u16)
+            NvmeCommand::create_io_completion_queue(c_id, q_id, addr, (QUEUE_LENGTH - 1) as u16, true)
         })?;
         let addr = dev.io_sq.get_addr();
         println!("Requesting i/o submission queue");
@@ -434,35 +1276,312 @@ impl NvmeDevice {
                 addr,
                 (QUEUE_LENGTH - 1) as u16,
                 q_id,
+                QueuePriority::Medium as u8,
+                true,
             )
         })?;
         dev.q_id += 1;
 
         dev.identify_controller()?;
-        let ns = dev.identify_namespace_list(0);
+        if let Err(e) = dev.enable_host_memory_buffer() {
+            eprintln!("Host memory buffer not enabled: {e}");
+        }
+        dev.refresh_namespaces();
+
+        Ok(dev)
+    }
+
+    // Grants the controller its preferred host memory buffer size (HMPRE) in huge page chunks,
+    // as long as at least the minimum size (HMMIN) can be allocated
+    fn enable_host_memory_buffer(&mut self) -> Result<(), Box<dyn Error>> {
+        let page_size = 4096;
+        let preferred = self.ctrl.hmpre as usize * page_size;
+        let minimum = self.ctrl.hmmin as usize * page_size;
+        if preferred == 0 {
+            return Ok(());
+        }
+        // Every chunk is a physically contiguous huge page
+        if self.ctrl.hmminds as usize * page_size > HUGE_PAGE_SIZE {
+            return Err("Minimum host memory buffer descriptor size exceeds a huge page".into());
+        }
+        let max_chunks = match self.ctrl.hmmaxd {
+            0 => HMB_MAX_DESCRIPTORS,
+            hmmaxd => (hmmaxd as usize).min(HMB_MAX_DESCRIPTORS),
+        };
+
+        let mut chunks = Vec::new();
+        for _ in 0..preferred.div_ceil(HUGE_PAGE_SIZE).min(max_chunks) {
+            match Dma::allocate(HUGE_PAGE_SIZE) {
+                Ok(chunk) => chunks.push(chunk),
+                Err(_) => break,
+            }
+        }
+        let allocated = chunks.len() * HUGE_PAGE_SIZE;
+        if allocated == 0 || allocated < minimum {
+            return Err(format!("Could only allocate {allocated} of the minimum {minimum} bytes").into());
+        }
+        // Chunks are whole huge pages, the controller is never granted more than it prefers
+        let size = allocated.min(preferred);
+
+        let mut descriptors: Dma<[HostMemoryBufferDescriptor; HMB_MAX_DESCRIPTORS]> = Dma::allocate(4096)?;
+        let mut remaining = size;
+        for (descriptor, chunk) in descriptors.iter_mut().zip(&chunks) {
+            let chunk_size = remaining.min(HUGE_PAGE_SIZE);
+            *descriptor = HostMemoryBufferDescriptor {
+                badd: chunk.phys as u64,
+                bsize: (chunk_size / page_size) as u32,
+                _rsvd: 0,
+            };
+            remaining -= chunk_size;
+        }
+
+        let hsize = (size / page_size) as u32;
+        let (hmdl, hmdlec) = (descriptors.phys as u64, chunks.len() as u32);
+        self.submit_and_complete_admin(|c_id, _| {
+            NvmeCommand::set_host_memory_buffer(c_id, true, false, hsize, hmdl, hmdlec)
+        })?;
+        println!("Host memory buffer: {} MiB in {} chunks", size >> 20, chunks.len());
+        self.hmb = Some(HostMemoryBuffer { chunks, size, descriptors });
+        Ok(())
+    }
+
+    /// Returns the size of the host memory buffer granted to the controller, 0 if none
+    pub fn host_memory_buffer_size(&self) -> usize {
+        self.hmb.as_ref().map_or(0, |hmb| hmb.size)
+    }
+
+    /// Takes the host memory buffer back from the controller, it can't be used afterwards
+    pub fn disable_host_memory_buffer(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.hmb.is_none() {
+            return Ok(());
+        }
+        self.submit_and_complete_admin_timeout(
+            |c_id, _| NvmeCommand::set_host_memory_buffer(c_id, false, false, 0, 0, 0),
+            HMB_DISABLE_TIMEOUT,
+        )?;
+        self.hmb = None;
+        Ok(())
+    }
+
+    /// Disables the host memory buffer and performs a normal controller shutdown,
+    /// so cached data is flushed before power is removed. The device can't be used afterwards.
+    pub fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        self.disable_host_memory_buffer()?;
+
+        // CC.SHN = 01b: normal shutdown
+        let cc = self.get_reg32(NvmeRegs32::CC as u32) & !(0b11 << 14);
+        self.set_reg32(NvmeRegs32::CC as u32, cc | (1 << 14));
+
+        // RTD3E is the worst case shutdown latency in microseconds, 0 if not reported
+        let timeout = match self.ctrl.rtd3e {
+            0 => Duration::from_secs(10),
+            rtd3e => Duration::from_micros(rtd3e as u64),
+        };
+        let start = Instant::now();
+        // CSTS.SHST = 10b: shutdown processing complete
+        while (self.get_reg32(NvmeRegs32::CSTS as u32) >> 2) & 0b11 != 0b10 {
+            if start.elapsed() > timeout {
+                return Err("Controller shutdown timed out".into());
+            }
+            spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Whether the controller arbitrates with weighted round robin (CC.AMS = 001b), so queue priorities apply
+    pub fn weighted_round_robin(&self) -> bool {
+        (self.get_reg32(NvmeRegs32::CC as u32) >> 11) & 0x7 == 1
+    }
+
+    /// Returns the arbitration burst (commands fetched at once as a power of two, 7 = unlimited)
+    /// and the weights of the high, medium and low priority classes
+    pub fn arbitration(&mut self) -> Result<(u8, [u16; 3]), Box<dyn Error>> {
+        match self.get_feature(FeatureId::Arbitration, FeatureSelect::Current)? {
+            Feature::Arbitration { burst, low, medium, high } => {
+                Ok((burst, [high as u16 + 1, medium as u16 + 1, low as u16 + 1]))
+            }
+            _ => Err("Unexpected feature returned".into()),
+        }
+    }
+
+    /// Sets the arbitration burst and the weights (1 to 256) of the high, medium and low priority classes.
+    /// The weights only apply with weighted round robin arbitration.
+    pub fn set_arbitration(&mut self, burst: u8, weights: [u16; 3], save: bool) -> Result<(), Box<dyn Error>> {
+        if burst > 7 {
+            return Err(format!("Arbitration burst {burst} is out of range").into());
+        }
+        if weights.iter().any(|&weight| !(1..=256).contains(&weight)) {
+            return Err("Arbitration weights need to be between 1 and 256".into());
+        }
+        let [high, medium, low] = weights.map(|weight| (weight - 1) as u8);
+        self.set_feature(Feature::Arbitration { burst, low, medium, high }, save)?;
+        Ok(())
+    }
+
+    pub fn pci_addr(&self) -> &str {
+        &self.pci_addr
+    }
+
+    /// Starts collecting detailed statistics of the device's I/O queue.
+    /// They cost a lock and a clock read per command, so they're off by default.
+    pub fn enable_stats(&mut self) {
+        let stats = self.stats_registry.register(1, self.io_sq.len());
+        self.io_sq.stats = Some(stats.clone());
+        self.io_cq.stats = Some(stats);
+    }
+
+    pub fn disable_stats(&mut self) {
+        self.io_sq.stats = None;
+        self.io_cq.stats = None;
+        self.stats_registry.unregister(1);
+    }
+
+    /// Snapshot of the detailed statistics of the device's I/O queue and of every queue pair they are enabled on.
+    /// Unless they are enabled on the device's I/O queue, its commands are counted from `stats`.
+    pub fn stats_snapshot(&self) -> NvmeStats {
+        let mut snapshot = self.stats_registry.snapshot();
+        if self.io_sq.stats.is_none() {
+            snapshot.submissions += self.stats.submissions;
+            snapshot.completions += self.stats.completions;
+        }
+        snapshot
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = NvmeStats::default();
+        self.stats_registry.reset();
+    }
+
+    /// Handle to enable statistics on queue pairs and take snapshots from another thread,
+    /// it stays valid while the device is in use
+    pub fn stats_registry(&self) -> StatsRegistry {
+        self.stats_registry.clone()
+    }
+
+    /// Starts tracing the admin queue (`q_id` 0) or the device's I/O queue (`q_id` 1), see `NvmeQueuePair::enable_trace`
+    pub fn enable_trace(&mut self, q_id: u16, capacity: usize) -> Result<SharedTrace, Box<dyn Error>> {
+        let trace = TraceRing::shared(q_id, capacity);
+        let (sq, cq) = self.traced_queues(q_id)?;
+        sq.trace = Some(trace.clone());
+        cq.trace = Some(trace.clone());
+        Ok(trace)
+    }
+
+    /// Stops tracing queue `q_id`, returns the ring if tracing was enabled
+    pub fn disable_trace(&mut self, q_id: u16) -> Result<Option<SharedTrace>, Box<dyn Error>> {
+        let (sq, cq) = self.traced_queues(q_id)?;
+        cq.trace = None;
+        Ok(sq.trace.take())
+    }
+
+    fn traced_queues(&mut self, q_id: u16) -> Result<(&mut NvmeSubQueue, &mut NvmeCompQueue), Box<dyn Error>> {
+        match q_id {
+            0 => Ok((&mut self.admin_sq, &mut self.admin_cq)),
+            1 => Ok((&mut self.io_sq, &mut self.io_cq)),
+            _ => Err("Only the admin and the device's I/O queue can be traced, trace queue pairs directly".into()),
+        }
+    }
+
+    /// Rebuilds `namespaces` from the controller's active namespace list
+    pub fn refresh_namespaces(&mut self) {
+        self.namespaces.clear();
+        let ns = self.identify_namespace_list(0);
         
         for n in ns {
             println!("ns_id: {n}");
-            dev.identify_namespace(n);
+            self.identify_namespace(n);
         }
         
-        if((dev.get_reg64(NvmeRegs64::CAP as u64) >> 37) & 0x40 != 0) {
-            let zns_ns = dev.identify_zns_namespace_list(0);
+        if((self.get_reg64(NvmeRegs64::CAP as u64) >> 37) & 0x40 != 0) {
+            let zns_ns = self.identify_zns_namespace_list(0);
             for n in zns_ns {
                 println!("ns_id: {n} supports zns");
-                dev.identify_zns_namespace(n)
+                self.identify_zns_namespace(n)
             }
         }
         else {
             println!("ZNS is not supported!")
         }
+    }
 
-        Ok(dev)
+    /// Creates a namespace of `size` blocks with `capacity` allocated blocks, using LBA format index `lba_format`
+    /// and command set `csi` (0 = NVM, 2 = ZNS). Returns the id of the new namespace, which still needs to be attached.
+    pub fn create_namespace(&mut self, size: u64, capacity: u64, lba_format: u8, csi: u8) -> Result<u32, Box<dyn Error>> {
+        self.check_namespace_management()?;
+        if lba_format > 0xF {
+            return Err("Invalid LBA format index".into());
+        }
+
+        // Only the host specified fields of the identify namespace data structure are used
+        let mut data: IdentifyNamespaceData = unsafe { std::mem::zeroed() };
+        data.nsze = size;
+        data.ncap = capacity;
+        data.flbas = lba_format;
+        unsafe { *(self.buffer.virt as *mut IdentifyNamespaceData) = data };
+
+        let entry = self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::namespace_management(c_id, addr, 0, 0, csi)
+        })?;
+        let ns_id = entry.command_specific1;
+        println!("Created namespace {ns_id}");
+
+        self.refresh_namespaces();
+        Ok(ns_id)
+    }
+
+    /// Deletes the namespace `ns_id`, or all namespaces if `ns_id` is `0xFFFF_FFFF`
+    pub fn delete_namespace(&mut self, ns_id: u32) -> Result<(), Box<dyn Error>> {
+        self.check_namespace_management()?;
+        self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::namespace_management(c_id, addr, ns_id, 1, 0)
+        })?;
+        println!("Deleted namespace {ns_id}");
+
+        self.refresh_namespaces();
+        Ok(())
+    }
+
+    /// Attaches the namespace `ns_id` to this controller
+    pub fn attach_namespace(&mut self, ns_id: u32) -> Result<(), Box<dyn Error>> {
+        self.namespace_attachment(ns_id, false)
+    }
+
+    /// Detaches the namespace `ns_id` from this controller
+    pub fn detach_namespace(&mut self, ns_id: u32) -> Result<(), Box<dyn Error>> {
+        self.namespace_attachment(ns_id, true)
+    }
+
+    fn namespace_attachment(&mut self, ns_id: u32, detach: bool) -> Result<(), Box<dyn Error>> {
+        self.check_namespace_management()?;
+
+        // Controller list with this controller as the only entry
+        let list = self.buffer.virt as *mut u16;
+        unsafe {
+            std::ptr::write_bytes(list, 0, 2048);
+            *list = 1;
+            *list.add(1) = self.ctrl.cntlid;
+        }
+
+        self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::namespace_attachment(c_id, addr, ns_id, detach as u8)
+        })?;
+
+        self.refresh_namespaces();
+        Ok(())
+    }
+
+    fn check_namespace_management(&self) -> Result<(), Box<dyn Error>> {
+        // OACS bit 3: Namespace Management and Attachment commands
+        if self.ctrl.oacs & (1 << 3) == 0 {
+            return Err("Namespace Management is not supported by the controller".into());
+        }
+        Ok(())
     }
 
     pub fn identify_controller(&mut self) -> Result<(), Box<dyn Error>> {
         println!("Trying to identify controller");
         let _entry = self.submit_and_complete_admin(NvmeCommand::identify_controller);
+        self.ctrl = unsafe { *(self.buffer.virt as *const IdentifyControllerData) };
 
         println!("Dumping identify controller");
         let mut serial = String::new();
@@ -503,41 +1622,80 @@ impl NvmeDevice {
 
     // 1 to 1 Submission/Completion Queue Mapping
     pub fn create_io_queue_pair(&mut self, len: usize) -> Result<NvmeQueuePair, Box<dyn Error>> {
+        self.create_io_queue_pair_with_priority(len, QueuePriority::Medium)
+    }
+
+    /// Like `create_io_queue_pair`, placing the submission queue into the `priority` class.
+    /// Classes other than medium need weighted round robin arbitration, see `weighted_round_robin`.
+    pub fn create_io_queue_pair_with_priority(
+        &mut self,
+        len: usize,
+        priority: QueuePriority,
+    ) -> Result<NvmeQueuePair, Box<dyn Error>> {
+        if priority != QueuePriority::Medium && !self.weighted_round_robin() {
+            return Err("Queue priorities need weighted round robin arbitration".into());
+        }
         let q_id = self.q_id;
         println!("Requesting i/o queue pair with id {q_id}");
 
+        // CAP.MQES is 0's based, CAP.CQR requires physically contiguous queues
+        let cap = self.get_reg64(NvmeRegs64::CAP as u64);
+        let max_len = (cap & 0xFFFF) as usize + 1;
+        if !(2..=max_len).contains(&len) {
+            return Err(format!("Queue length {len} is out of range, the controller supports 2 to {max_len} entries").into());
+        }
+        let contiguous_required = (cap >> 16) & 1 == 1;
+
         let offset = 0x1000 + ((4 << self.dstrd) * (2 * q_id + 1) as usize);
         assert!(offset <= self.len - 4, "SQ doorbell offset out of bounds");
 
+        // Both queues are allocated and located first, so nothing is left on the controller if that fails
         let dbl = self.addr as usize + offset;
+        let mut comp_queue: NvmeCompQueue = NvmeCompQueue::new(len, dbl)?;
+        let cq_memory = QueueMemory::locate(comp_queue.pages()?, contiguous_required)?;
+        let dbl = self.addr as usize + 0x1000 + ((4 << self.dstrd) * (2 * q_id) as usize);
+        let mut sub_queue = NvmeSubQueue::new(len, dbl)?;
+        let sq_memory = QueueMemory::locate(sub_queue.pages()?, contiguous_required)?;
 
-        let comp_queue: NvmeCompQueue = NvmeCompQueue::new(len, dbl)?;
-        let comp = self.submit_and_complete_admin(|c_id, _| {
+        self.submit_and_complete_admin(|c_id, _| {
             NvmeCommand::create_io_completion_queue(
                 c_id,
                 q_id,
-                comp_queue.get_addr(),
+                cq_memory.addr,
                 (len - 1) as u16,
+                cq_memory.contiguous(),
             )
         })?;
-
-        let dbl = self.addr as usize + 0x1000 + ((4 << self.dstrd) * (2 * q_id) as usize);
-        let sub_queue = NvmeSubQueue::new(len, dbl)?;
-        let comp = self.submit_and_complete_admin(|c_id, _| {
+        let created = self.submit_and_complete_admin(|c_id, _| {
             NvmeCommand::create_io_submission_queue(
                 c_id,
                 q_id,
-                sub_queue.get_addr(),
+                sq_memory.addr,
                 (len - 1) as u16,
                 q_id,
+                priority as u8,
+                sq_memory.contiguous(),
             )
-        })?;
+        });
+        if let Err(e) = created {
+            if let Err(delete) = self.submit_and_complete_admin(|c_id, _| NvmeCommand::delete_io_completion_queue(c_id, q_id)) {
+                eprintln!("Deleting completion queue {q_id} failed: {delete}");
+            }
+            return Err(e);
+        }
 
         self.q_id += 1;
         Ok(NvmeQueuePair {
             id: q_id,
             sub_queue,
             comp_queue,
+            submit_mode: SubmitMode::default(),
+            doorbells: Doorbells::default(),
+            prp_lists: [cq_memory.prp_list, sq_memory.prp_list],
+            acwu: self.compare_and_write_unit().ok(),
+            copy_supported: self.ctrl.oncs & (1 << 8) != 0,
+            reservations_supported: self.ctrl.oncs & (1 << 5) != 0,
+            extended_host_id: self.ctrl.ctratt & 1 == 1,
         })
     }
 
@@ -549,6 +1707,7 @@ impl NvmeDevice {
         self.submit_and_complete_admin(|c_id, _| {
             NvmeCommand::delete_io_completion_queue(c_id, qpair.id)
         })?;
+        self.stats_registry.unregister(qpair.id);
         Ok(())
     }
 
@@ -608,15 +1767,22 @@ impl NvmeDevice {
         println!("Namespace {id}, Size: {size}, Blocks: {blocks}, Block size: {block_size}");
         let mssrl = namespace_data.mssrl;
         let mcl = namespace_data.mcl;
-        println!("Copy command mssrl {} and mcl {}", mssrl,mcl);
+        let msrc = namespace_data.msrc;
+        let endgid = namespace_data.endgid;
+        println!("Copy command mssrl {} mcl {} and msrc {}", mssrl, mcl, msrc);
         let namespace = NvmeNamespace {
             id,
             blocks,
             block_size,
             flba_idx,
+            mssrl,
+            mcl,
+            msrc,
+            endgid,
             zns_info : None
         };
         self.namespaces.insert(id, namespace);
+        self.stats_registry.set_block_size(id, block_size);
         namespace
     }
 
@@ -657,11 +1823,43 @@ impl NvmeDevice {
         &mut self, 
         ns_id: u32,
         data: &impl DmaSlice, 
-        mut lba: u64) -> Result<(), Box<dyn Error>> {
+        lba: u64) -> Result<(), Box<dyn Error>> {
+        self.write_stream(ns_id, data, lba, None)
+    }
+
+    /// Like `write`, tagging the data with `stream_id` so the controller keeps it together with the rest of the stream
+    pub fn write_stream(
+        &mut self,
+        ns_id: u32,
+        data: &impl DmaSlice,
+        mut lba: u64,
+        stream_id: Option<u16>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.write_directive(ns_id, data, lba, stream_id.map(|id| (DTYPE_STREAMS, id)))
+    }
+
+    /// Like `write`, placing the data with the FDP placement identifier `placement_id` if set
+    pub fn write_placement(
+        &mut self,
+        ns_id: u32,
+        data: &impl DmaSlice,
+        lba: u64,
+        placement_id: Option<u16>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.write_directive(ns_id, data, lba, placement_id.map(|id| (DTYPE_DATA_PLACEMENT, id)))
+    }
+
+    fn write_directive(
+        &mut self,
+        ns_id: u32,
+        data: &impl DmaSlice,
+        mut lba: u64,
+        directive: Option<(u8, u16)>,
+    ) -> Result<(), Box<dyn Error>> {
         let ns = *self.namespaces.get(&ns_id).unwrap();
         for chunk in data.chunks(2 * 4096) {
             let blocks = (chunk.slice.len() as u64 + ns.block_size - 1) / ns.block_size;
-            self.namespace_io(ns_id, blocks, lba, chunk.phys_addr as u64, true)?;
+            self.namespace_io(ns_id, blocks, lba, chunk.phys_addr as u64, true, directive)?;
             lba += blocks;
         }
 
@@ -677,7 +1875,7 @@ impl NvmeDevice {
         let ns = *self.namespaces.get(&ns_id).unwrap();
         for chunk in dest.chunks(2 * 4096) {
             let blocks = (chunk.slice.len() as u64 + ns.block_size - 1) / ns.block_size;
-            self.namespace_io(ns_id, blocks, lba, chunk.phys_addr as u64, false)?;
+            self.namespace_io(ns_id, blocks, lba, chunk.phys_addr as u64, false, None)?;
             lba += blocks;
         }
         Ok(())
@@ -687,13 +1885,45 @@ impl NvmeDevice {
         &mut self, 
         ns_id: u32, 
         data: &[u8], 
-        mut lba: u64
+        lba: u64
+    ) -> Result<(), Box<dyn Error>> {
+        self.write_copied_stream(ns_id, data, lba, None)
+    }
+
+    /// Like `write_copied`, tagging the data with `stream_id` if set
+    pub fn write_copied_stream(
+        &mut self,
+        ns_id: u32,
+        data: &[u8],
+        lba: u64,
+        stream_id: Option<u16>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.write_copied_directive(ns_id, data, lba, stream_id.map(|id| (DTYPE_STREAMS, id)))
+    }
+
+    /// Like `write_copied`, placing the data with the FDP placement identifier `placement_id` if set
+    pub fn write_copied_placement(
+        &mut self,
+        ns_id: u32,
+        data: &[u8],
+        lba: u64,
+        placement_id: Option<u16>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.write_copied_directive(ns_id, data, lba, placement_id.map(|id| (DTYPE_DATA_PLACEMENT, id)))
+    }
+
+    fn write_copied_directive(
+        &mut self,
+        ns_id: u32,
+        data: &[u8],
+        mut lba: u64,
+        directive: Option<(u8, u16)>,
     ) -> Result<(), Box<dyn Error>> {
         let ns = *self.namespaces.get(&ns_id).unwrap();
         for chunk in data.chunks(128 * 4096) {
             self.buffer[..chunk.len()].copy_from_slice(chunk);
             let blocks = (chunk.len() as u64 + ns.block_size - 1) / ns.block_size;
-            self.namespace_io(ns_id, blocks, lba, self.buffer.phys as u64, true)?;
+            self.namespace_io(ns_id, blocks, lba, self.buffer.phys as u64, true, directive)?;
             lba += blocks;
         }
 
@@ -709,7 +1939,7 @@ impl NvmeDevice {
         let ns = *self.namespaces.get(&ns_id).unwrap();
         for chunk in dest.chunks_mut(128 * 4096) {
             let blocks = (chunk.len() as u64 + ns.block_size - 1) / ns.block_size;
-            self.namespace_io(ns_id, blocks, lba, self.buffer.phys as u64, false)?;
+            self.namespace_io(ns_id, blocks, lba, self.buffer.phys as u64, false, None)?;
             lba += blocks;
             chunk.copy_from_slice(&self.buffer[..chunk.len()]);
         }
@@ -859,6 +2089,7 @@ impl NvmeDevice {
         lba: u64,
         addr: u64,
         write: bool,
+        directive: Option<(u8, u16)>,
     ) -> Result<(), Box<dyn Error>> {
         assert!(blocks > 0);
         assert!(blocks <= 0x1_0000);
@@ -870,14 +2101,18 @@ impl NvmeDevice {
         let ptr1 = self.get_prp2(bytes, addr);
 
         let entry = if write {
-            NvmeCommand::io_write(
+            let entry = NvmeCommand::io_write(
                 self.io_sq.tail as u16,
                 ns_id,
                 lba,
                 blocks as u16 - 1,
                 addr,
                 ptr1,
-            )
+            );
+            match directive {
+                Some((dtype, dspec)) => entry.directive(dtype, dspec),
+                None => entry,
+            }
         } else {
             NvmeCommand::io_read(
                 self.io_sq.tail as u16,
@@ -893,20 +2128,23 @@ impl NvmeDevice {
         self.stats.submissions += 1;
 
         self.write_reg_idx(NvmeArrayRegs::SQyTDBL, q_id as u16, tail as u32);
-        self.io_sq.head = self.complete_io(1).unwrap().sq_head as usize;
-        Ok(())
+        match self.complete_io(1) {
+            Ok(completion) => {
+                self.io_sq.head = completion.sq_head as usize;
+                Ok(())
+            }
+            Err(completion) => {
+                self.io_sq.head = completion.sq_head as usize;
+                Err(io_error(completion, "I/O command failed"))
+            }
+        }
     }
 
     fn submit_and_complete_admin<F: FnOnce(u16, usize) -> NvmeCommand>(
         &mut self,
         cmd_init: F,
     ) -> Result<NvmeCompletion, Box<dyn Error>> {
-        let cid = self.admin_sq.tail;
-        let tail = self.admin_sq.submit(cmd_init(cid as u16, self.buffer.phys));
-        self.write_reg_idx(NvmeArrayRegs::SQyTDBL, 0, tail as u32);
-
-        let (head, entry, _) = self.admin_cq.complete_spin();
-        self.write_reg_idx(NvmeArrayRegs::CQyHDBL, 0, head as u32);
+        let entry = self.submit_and_complete_admin_unchecked(cmd_init, Duration::MAX)?;
         let status = entry.status >> 1;
         if status != 0 {
             eprintln!(
@@ -920,45 +2158,993 @@ impl NvmeDevice {
         Ok(entry)
     }
 
-    pub fn clear_namespace(&mut self, ns_id: Option<u32>) {
-        let ns_id = if let Some(ns_id) = ns_id {
-            assert!(self.namespaces.contains_key(&ns_id));
-            ns_id
-        } else {
-            0xFFFF_FFFF
+    /// Downloads `image` to the controller in chunks aligned to the firmware update granularity.
+    /// The image still needs to be committed to a slot with `firmware_commit`.
+    pub fn firmware_download(&mut self, image: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.check_firmware_support()?;
+        if image.is_empty() || !image.len().is_multiple_of(4) {
+            return Err("Firmware image size needs to be a non-zero multiple of 4 bytes".into());
+        }
+
+        // FWUG is in 4KiB units, 0 means no information and 0xFF no restriction
+        let max_transfer = self.max_transfer_size();
+        let chunk_size = match self.ctrl.fwug {
+            0 | 0xFF => max_transfer,
+            fwug => {
+                let granularity = fwug as usize * 4096;
+                if granularity > max_transfer {
+                    return Err("Firmware update granularity exceeds the maximum transfer size".into());
+                }
+                max_transfer / granularity * granularity
+            }
         };
-        self.submit_and_complete_admin(|c_id, _| NvmeCommand::format_nvm(c_id, ns_id));
+
+        let mut offset = 0;
+        for chunk in image.chunks(chunk_size) {
+            self.buffer[..chunk.len()].copy_from_slice(chunk);
+            let numd = (chunk.len() / 4 - 1) as u32;
+            let ofst = (offset / 4) as u32;
+            let ptr0 = self.buffer.phys as u64;
+            let ptr1 = self.get_prp2(chunk.len() as u64, ptr0);
+            self.submit_and_complete_admin(|c_id, _| {
+                NvmeCommand::firmware_image_download(c_id, numd, ofst, ptr0, ptr1)
+            })?;
+            offset += chunk.len();
+        }
+        println!("Downloaded firmware image of {} bytes", image.len());
+
+        Ok(())
     }
 
-    // Unfortunately not supported by the WD ZNS SSD :(
-    // TODO maybe use MCL instead of 128
-    pub fn copy(&mut self, ns_id: u32, mut src: u64, mut dest: u64, mut len: u64) -> Result<(), Box<dyn Error>> {
-        while len > 0 {
-            let current_len = std::cmp::min(len, 128);
-            let mut data = self.buffer.virt as *mut SourceRangeEntriesDescriptorFormat0;
-            unsafe {
-                (*data).slba = src;
-                (*data).n_blocks = current_len as u16 - 1;
-                //TODO is this necessary?
-                (*data)._reserved1 = 0;
-                (*data)._reserved2 = 0;
-                (*data).rest = [0; 4076];
+    /// Commits the downloaded image to `slot` (0 lets the controller choose) or activates an existing slot.
+    /// Returns whether and which kind of reset is needed to run the new image.
+    pub fn firmware_commit(&mut self, slot: u8, action: FirmwareCommitAction) -> Result<FirmwareActivation, Box<dyn Error>> {
+        self.check_firmware_support()?;
+        let n_slots = (self.ctrl.frmw >> 1) & 0x7;
+        if slot > n_slots {
+            return Err(format!("Invalid firmware slot {slot}, the controller has {n_slots} slots").into());
+        }
+
+        let entry = self.submit_and_complete_admin_unchecked(
+            |c_id, _| NvmeCommand::firmware_commit(c_id, slot, action as u8),
+            Duration::MAX,
+        )?;
+        let status = entry.status >> 1;
+        let (sc, sct) = (status & 0xFF, (status >> 8) & 0x7);
+        match (sct, sc) {
+            (0, 0) => match action {
+                FirmwareCommitAction::ReplaceAndActivateImmediately => Ok(FirmwareActivation::Activated),
+                FirmwareCommitAction::Replace => Ok(FirmwareActivation::NotActivated),
+                _ => Ok(FirmwareActivation::NextReset),
+            },
+            (1, 0x0B) => Ok(FirmwareActivation::ConventionalReset),
+            (1, 0x10) => Ok(FirmwareActivation::SubsystemReset),
+            (1, 0x11) => Ok(FirmwareActivation::ControllerReset),
+            (1, 0x06) => Err("Invalid firmware slot".into()),
+            (1, 0x07) => Err("Invalid firmware image".into()),
+            (1, 0x12) => Err("Firmware activation would exceed the maximum time for activation".into()),
+            (1, 0x13) => Err("Firmware activation prohibited".into()),
+            _ => {
+                eprintln!(
+                    "Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
+                    status, sc, sct
+                );
+                Err("Firmware commit failed".into())
             }
-            let ptr0 = self.buffer.phys as u64;
+        }
+    }
 
-            let entry = NvmeCommand::copy(self.io_sq.tail as u16, ns_id, dest, ptr0);
-            let tail = self.io_sq.submit(entry);
-            self.write_reg_idx(NvmeArrayRegs::SQyTDBL, 1, tail as u32);
-            self.io_sq.head = self.complete_io(1).unwrap().sq_head as usize;
+    /// Downloads `image` and commits it to `slot` with `action`
+    pub fn firmware_update(
+        &mut self,
+        image: &[u8],
+        slot: u8,
+        action: FirmwareCommitAction,
+    ) -> Result<FirmwareActivation, Box<dyn Error>> {
+        self.firmware_download(image)?;
+        self.firmware_commit(slot, action)
+    }
 
-            len -= current_len;
-            src += current_len;
-            dest += current_len;
+    /// Reads the Firmware Slot Information log page
+    pub fn firmware_slot_info(&mut self) -> Result<FirmwareSlotInfo, Box<dyn Error>> {
+        let mut log = [0u8; 512];
+        self.get_log_page(LID_FIRMWARE_SLOT, 0, 0, &mut log)?;
+        let afi = log[0];
+
+        let mut revisions = Vec::with_capacity(7);
+        for i in 0..7 {
+            let start = 8 + i * 8;
+            let revision: String = log[start..start + 8]
+                .iter()
+                .take_while(|&&b| b != 0)
+                .map(|&b| b as char)
+                .collect();
+            revisions.push(revision.trim().to_string());
         }
 
+        let frmw = self.ctrl.frmw;
+        let next_slot = (afi >> 4) & 0x7;
+        Ok(FirmwareSlotInfo {
+            active_slot: afi & 0x7,
+            next_slot: if next_slot == 0 { None } else { Some(next_slot) },
+            revisions,
+            n_slots: (frmw >> 1) & 0x7,
+            slot1_read_only: frmw & 1 == 1,
+            activation_without_reset: (frmw >> 4) & 1 == 1,
+        })
+    }
+
+    fn check_firmware_support(&self) -> Result<(), Box<dyn Error>> {
+        // OACS bit 2: Firmware Commit and Firmware Image Download commands
+        if self.ctrl.oacs & (1 << 2) == 0 {
+            return Err("Firmware management is not supported by the controller".into());
+        }
         Ok(())
     }
 
+    // Maximum data transfer size, assuming a memory page size of 4KiB and limited to our buffer
+    fn max_transfer_size(&self) -> usize {
+        match self.ctrl.mdts {
+            0 => HUGE_PAGE_SIZE,
+            mdts => (4096usize << mdts).min(HUGE_PAGE_SIZE),
+        }
+    }
+
+    /// Starts a sanitize operation on the whole NVM subsystem and polls the Sanitize Status log page
+    /// until it finishes, passing every status to `progress`.
+    /// `ause` allows exiting the failure mode without another successful sanitize,
+    /// `no_dealloc` prevents deallocating the sanitized blocks.
+    pub fn sanitize<F: FnMut(&SanitizeStatus)>(
+        &mut self,
+        action: SanitizeAction,
+        ause: bool,
+        no_dealloc: bool,
+        mut progress: F,
+    ) -> Result<SanitizeStatus, Box<dyn Error>> {
+        // SANICAP bits 0-2: Crypto Erase, Block Erase and Overwrite support
+        let sanicap = self.ctrl.sanicap;
+        let (sanact, supported, owpass, oipbp, ovrpat) = match action {
+            SanitizeAction::BlockErase => (2, sanicap & (1 << 1) != 0, 0, false, 0),
+            SanitizeAction::CryptoErase => (4, sanicap & 1 != 0, 0, false, 0),
+            SanitizeAction::Overwrite { pattern, passes, invert } => {
+                if !(1..=16).contains(&passes) {
+                    return Err("Overwrite passes need to be between 1 and 16".into());
+                }
+                // OWPASS of 0 means 16 passes
+                (3, sanicap & (1 << 2) != 0, passes & 0xF, invert, pattern)
+            }
+        };
+        if !supported {
+            return Err(format!("Sanitize action {:?} is not supported by the controller", action).into());
+        }
+
+        self.submit_and_complete_admin(|c_id, _| {
+            NvmeCommand::sanitize(c_id, sanact, ause, owpass, oipbp, no_dealloc, ovrpat)
+        })?;
+
+        loop {
+            let status = self.sanitize_status()?;
+            progress(&status);
+            match status.state {
+                SanitizeState::InProgress => std::thread::sleep(SANITIZE_POLL_INTERVAL),
+                SanitizeState::Failed => return Err("Sanitize operation failed".into()),
+                _ => return Ok(status),
+            }
+        }
+    }
+
+    /// Reads the Sanitize Status log page
+    pub fn sanitize_status(&mut self) -> Result<SanitizeStatus, Box<dyn Error>> {
+        let log: SanitizeStatusLog = self.get_log_page_as(LID_SANITIZE_STATUS, 0)?;
+
+        let sstat = log.sstat;
+        let state = match sstat & 0x7 {
+            0 => SanitizeState::NeverSanitized,
+            1 => SanitizeState::Completed,
+            2 => SanitizeState::InProgress,
+            3 => SanitizeState::Failed,
+            4 => SanitizeState::CompletedNoDeallocate,
+            other => SanitizeState::Unknown(other as u8),
+        };
+        Ok(SanitizeStatus {
+            progress: log.sprog,
+            state,
+            overwrite_passes: ((sstat >> 3) & 0x1F) as u8,
+            global_data_erased: (sstat >> 8) & 1 == 1,
+            cdw10: log.scdw10,
+        })
+    }
+
+    /// Reads log page `lid` for namespace `ns_id` (0 or `0xFFFF_FFFF` for controller wide logs)
+    /// starting at byte `offset` into `dest`, splitting the transfer if it exceeds the maximum transfer size
+    pub fn get_log_page(&mut self, lid: u8, ns_id: u32, offset: u64, dest: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        self.get_log_page_scoped(lid, ns_id, 0, 0, offset, dest)
+    }
+
+    // Like get_log_page, with the log specific parameter and identifier
+    fn get_log_page_scoped(
+        &mut self,
+        lid: u8,
+        ns_id: u32,
+        lsp: u8,
+        lsi: u16,
+        offset: u64,
+        dest: &mut [u8],
+    ) -> Result<(), Box<dyn Error>> {
+        if dest.is_empty() || !dest.len().is_multiple_of(4) || !offset.is_multiple_of(4) {
+            return Err("Log page transfers need to be dword sized and aligned".into());
+        }
+        let chunk_size = self.max_transfer_size();
+        // LPA bit 2: extended data for Get Log Page, needed for offsets and large transfers
+        if (offset != 0 || dest.len() > chunk_size.min(4 << 16)) && self.ctrl.lpa & (1 << 2) == 0 {
+            return Err("Log page offsets are not supported by the controller".into());
+        }
+
+        let mut offset = offset;
+        for chunk in dest.chunks_mut(chunk_size) {
+            let numd = (chunk.len() / 4 - 1) as u32;
+            let ptr0 = self.buffer.phys as u64;
+            let ptr1 = self.get_prp2(chunk.len() as u64, ptr0);
+            self.submit_and_complete_admin(|c_id, _| {
+                NvmeCommand::get_log_page(c_id, ns_id, numd, ptr0, ptr1, lid, offset).log_specific(lsp, lsi)
+            })?;
+            chunk.copy_from_slice(&self.buffer[..chunk.len()]);
+            offset += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    // Reads a log page into its packed representation
+    fn get_log_page_as<T: Copy>(&mut self, lid: u8, ns_id: u32) -> Result<T, Box<dyn Error>> {
+        let mut data = vec![0u8; std::mem::size_of::<T>()];
+        self.get_log_page(lid, ns_id, 0, &mut data)?;
+        Ok(unsafe { std::ptr::read_unaligned(data.as_ptr() as *const T) })
+    }
+
+    /// Reads the controller wide SMART / Health Information log page
+    pub fn smart_health_log(&mut self) -> Result<SmartHealthLog, Box<dyn Error>> {
+        self.get_log_page_as(LID_SMART_HEALTH, 0xFFFF_FFFF)
+    }
+
+    /// Reads the Error Information log page, returning only valid entries, newest first
+    pub fn error_log(&mut self) -> Result<Vec<ErrorInformationEntry>, Box<dyn Error>> {
+        let entries = self.ctrl.elpe as usize + 1;
+        let entry_size = std::mem::size_of::<ErrorInformationEntry>();
+        let mut data = vec![0u8; entries * entry_size];
+        self.get_log_page(LID_ERROR_INFORMATION, 0, 0, &mut data)?;
+
+        Ok(data
+            .chunks_exact(entry_size)
+            .map(|entry| unsafe { std::ptr::read_unaligned(entry.as_ptr() as *const ErrorInformationEntry) })
+            .filter(|entry| entry.error_count != 0)
+            .collect())
+    }
+
+    /// Reads the Changed Namespace List log page.
+    /// Returns `None` if more than 1024 namespaces changed and all of them need to be rescanned.
+    pub fn changed_namespace_list(&mut self) -> Result<Option<Vec<u32>>, Box<dyn Error>> {
+        let list: [u32; 1024] = self.get_log_page_as(LID_CHANGED_NAMESPACES, 0)?;
+        if list[0] == 0xFFFF_FFFF {
+            return Ok(None);
+        }
+        Ok(Some(list.iter().copied().take_while(|&id| id != 0).collect()))
+    }
+
+    /// Reads the Commands Supported and Effects log page
+    pub fn command_effects_log(&mut self) -> Result<CommandEffectsLog, Box<dyn Error>> {
+        // LPA bit 1: Commands Supported and Effects log page
+        if self.ctrl.lpa & (1 << 1) == 0 {
+            return Err("Commands Supported and Effects log page is not supported by the controller".into());
+        }
+        self.get_log_page_as(LID_COMMAND_EFFECTS, 0)
+    }
+
+    // Like submit_and_complete_admin, but for long running commands that shouldn't block forever
+    fn submit_and_complete_admin_timeout<F: FnOnce(u16, usize) -> NvmeCommand>(
+        &mut self,
+        cmd_init: F,
+        timeout: Duration,
+    ) -> Result<NvmeCompletion, Box<dyn Error>> {
+        let entry = self.submit_and_complete_admin_unchecked(cmd_init, timeout)?;
+        let status = entry.status >> 1;
+        if status != 0 {
+            eprintln!(
+                "Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
+                status,
+                status & 0xFF,
+                (status >> 8) & 0x7
+            );
+            return Err("Admin command failed".into());
+        }
+        Ok(entry)
+    }
+
+    // Returns the completion regardless of its status, for commands with meaningful error statuses
+    fn submit_and_complete_admin_unchecked<F: FnOnce(u16, usize) -> NvmeCommand>(
+        &mut self,
+        cmd_init: F,
+        timeout: Duration,
+    ) -> Result<NvmeCompletion, Box<dyn Error>> {
+        let cid = self.admin_sq.tail;
+        let tail = self.admin_sq.submit(cmd_init(cid as u16, self.buffer.phys));
+        self.write_reg_idx(NvmeArrayRegs::SQyTDBL, 0, tail as u32);
+
+        let start = Instant::now();
+        loop {
+            if let Some((head, entry, _)) = self.admin_cq.complete() {
+                self.write_reg_idx(NvmeArrayRegs::CQyHDBL, 0, head as u32);
+                self.admin_sq.head = entry.sq_head as usize;
+                // Asynchronous events can complete at any time, they're handled in poll_async_events
+                if entry.c_id & AER_CID_FLAG != 0 {
+                    self.aer_pending.push(entry);
+                } else if entry.c_id == cid as u16 {
+                    return Ok(entry);
+                }
+                continue;
+            }
+            if start.elapsed() > timeout {
+                return Err("Admin command timed out".into());
+            }
+            spin_loop();
+        }
+    }
+
+    /// Reads feature `id`, selecting the current, default or saved value
+    pub fn get_feature(&mut self, id: FeatureId, sel: FeatureSelect) -> Result<Feature, Box<dyn Error>> {
+        // CTRATT bit 0: 128 bit Host Identifier, read in the format the controller supports
+        let cdw11 = match id {
+            FeatureId::HostIdentifier => self.ctrl.ctratt & 1,
+            _ => 0,
+        };
+        let dw0 = self.get_feature_raw(id, sel as u8, cdw11)?;
+        Ok(Feature::decode(id, cdw11, dw0, &self.buffer[..id.data_len()]))
+    }
+
+    /// Reads the over (or under) temperature threshold of `sensor` (0 = composite) in Kelvin
+    pub fn get_temperature_threshold(&mut self, sensor: u8, under: bool, sel: FeatureSelect) -> Result<u16, Box<dyn Error>> {
+        let cdw11 = ((under as u32) << 20) | ((sensor as u32 & 0xF) << 16);
+        let dw0 = self.get_feature_raw(FeatureId::TemperatureThreshold, sel as u8, cdw11)?;
+        Ok(dw0 as u16)
+    }
+
+    /// Returns whether feature `id` is saveable, namespace specific and changeable
+    pub fn feature_capabilities(&mut self, id: FeatureId) -> Result<FeatureCapabilities, Box<dyn Error>> {
+        Ok(FeatureCapabilities::from(self.get_feature_raw(id, 3, 0)?))
+    }
+
+    /// Sets `feature`, persisting it across resets if `save` is set.
+    /// Returns completion dword 0, e.g. the allocated queues for `Feature::NumberOfQueues`.
+    pub fn set_feature(&mut self, feature: Feature, save: bool) -> Result<u32, Box<dyn Error>> {
+        // ONCS bit 4: Save field of Set Features and Select field of Get Features
+        if save && self.ctrl.oncs & (1 << 4) == 0 {
+            return Err("Saving features is not supported by the controller".into());
+        }
+        let (cdw11, data) = feature.encode();
+        self.buffer[..data.len()].copy_from_slice(&data);
+
+        let fid = feature.id() as u8;
+        let entry = self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::set_features(c_id, addr, fid, save, cdw11)
+        })?;
+        Ok(entry.command_specific1)
+    }
+
+    fn get_feature_raw(&mut self, id: FeatureId, sel: u8, cdw11: u32) -> Result<u32, Box<dyn Error>> {
+        if sel != 0 && self.ctrl.oncs & (1 << 4) == 0 {
+            return Err("Selecting feature values is not supported by the controller".into());
+        }
+        let fid = id as u8;
+        let entry = self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::get_features(c_id, addr, fid, sel, cdw11)
+        })?;
+        Ok(entry.command_specific1)
+    }
+
+    /// Returns the power state descriptors of the controller, indexed by power state
+    pub fn power_states(&self) -> Vec<PowerStateDescriptor> {
+        let psd = self.ctrl.psd;
+        psd.iter()
+            .take(self.ctrl.npss as usize + 1)
+            .map(PowerStateDescriptor::parse)
+            .collect()
+    }
+
+    /// Returns the power state the controller is currently in
+    pub fn current_power_state(&mut self) -> Result<u8, Box<dyn Error>> {
+        match self.get_feature(FeatureId::PowerManagement, FeatureSelect::Current)? {
+            Feature::PowerManagement { power_state, .. } => Ok(power_state),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Transitions the controller to power state `ps`
+    pub fn set_power_state(&mut self, ps: u8) -> Result<(), Box<dyn Error>> {
+        if ps > self.ctrl.npss {
+            return Err(format!("Power state {} not supported, the controller has {} states", ps, self.ctrl.npss as u16 + 1).into());
+        }
+        self.set_feature(Feature::PowerManagement { power_state: ps, workload_hint: 0 }, false)?;
+        Ok(())
+    }
+
+    /// Enables autonomous power state transitions with the table built by `policy`
+    pub fn configure_apst(&mut self, policy: ApstPolicy, save: bool) -> Result<(), Box<dyn Error>> {
+        let table = policy.table(&self.power_states())?;
+        self.set_apst(true, table, save)
+    }
+
+    /// Disables autonomous power state transitions
+    pub fn disable_apst(&mut self) -> Result<(), Box<dyn Error>> {
+        self.set_apst(false, [0; 32], false)
+    }
+
+    /// Returns whether APST is enabled and the current transition table
+    pub fn apst(&mut self) -> Result<(bool, [u64; 32]), Box<dyn Error>> {
+        self.check_apst_support()?;
+        match self.get_feature(FeatureId::AutonomousPowerStateTransition, FeatureSelect::Current)? {
+            Feature::AutonomousPowerStateTransition { enabled, table } => Ok((enabled, table)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_apst(&mut self, enabled: bool, table: [u64; 32], save: bool) -> Result<(), Box<dyn Error>> {
+        self.check_apst_support()?;
+        self.set_feature(Feature::AutonomousPowerStateTransition { enabled, table }, save)?;
+        Ok(())
+    }
+
+    fn check_apst_support(&self) -> Result<(), Box<dyn Error>> {
+        // APSTA bit 0: Autonomous Power State Transitions supported
+        if self.ctrl.apsta & 1 == 0 {
+            return Err("Autonomous power state transitions are not supported by the controller".into());
+        }
+        Ok(())
+    }
+
+    /// Enables notices in the Asynchronous Event Configuration feature
+    /// and keeps AERL + 1 Asynchronous Event Requests outstanding on the admin queue.
+    /// Events are only delivered by `poll_async_events`.
+    pub fn enable_async_events(&mut self) -> Result<(), Box<dyn Error>> {
+        // SMART / Health critical warnings and the namespace attribute and firmware activation notices the controller supports
+        let config = 0xFF | (self.ctrl.oaes & (0b11 << 8));
+        self.set_feature(Feature::AsyncEventConfig(config), false)?;
+
+        while self.aer_outstanding.len() < self.ctrl.aerl as usize + 1 {
+            // Ids of requests that are still outstanding, e.g. after some were aborted, are skipped
+            let c_id = (0..AER_CID_FLAG)
+                .map(|n| AER_CID_FLAG | n)
+                .find(|c_id| !self.aer_outstanding.contains(c_id))
+                .ok_or("No free Asynchronous Event Request command id")?;
+            self.submit_async_event_request(c_id);
+        }
+        Ok(())
+    }
+
+    /// Registers a callback that gets every asynchronous event delivered by `poll_async_events`
+    pub fn subscribe_async_events<F: FnMut(&AsyncEvent) + Send + 'static>(&mut self, callback: F) {
+        self.event_handlers.push(Box::new(callback));
+    }
+
+    /// Returns a channel receiving every asynchronous event delivered by `poll_async_events`
+    pub fn async_event_channel(&mut self) -> Receiver<AsyncEvent> {
+        let (tx, rx) = channel();
+        self.subscribe_async_events(move |event| {
+            let _ = tx.send(event.clone());
+        });
+        rx
+    }
+
+    /// Handles completed Asynchronous Event Requests: reads the associated log page to clear the event,
+    /// re-arms the request and passes the event to all subscribers. Returns the number of events delivered.
+    /// Every completed request is handled even if decoding one of the events fails, the first error is returned afterwards.
+    pub fn poll_async_events(&mut self) -> Result<usize, Box<dyn Error>> {
+        while let Some((head, entry, _)) = self.admin_cq.complete() {
+            self.write_reg_idx(NvmeArrayRegs::CQyHDBL, 0, head as u32);
+            self.admin_sq.head = entry.sq_head as usize;
+            if entry.c_id & AER_CID_FLAG != 0 {
+                self.aer_pending.push(entry);
+            }
+        }
+
+        let mut delivered = 0;
+        let mut error = None;
+        for entry in std::mem::take(&mut self.aer_pending) {
+            let c_id = entry.c_id;
+            self.aer_outstanding.remove(&c_id);
+            let status = entry.status >> 1;
+            if status != 0 {
+                // Aborted, e.g. Asynchronous Event Request Limit Exceeded, so it isn't re-armed
+                eprintln!(
+                    "Asynchronous Event Request Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
+                    status,
+                    status & 0xFF,
+                    (status >> 8) & 0x7
+                );
+                continue;
+            }
+
+            // Completed requests are re-armed with their command id, which keeps the ids unique
+            self.submit_async_event_request(c_id);
+            match self.decode_async_event(entry.command_specific1) {
+                Ok(event) => {
+                    for handler in self.event_handlers.iter_mut() {
+                        handler(&event);
+                    }
+                    delivered += 1;
+                }
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(delivered),
+        }
+    }
+
+    // Decodes completion dword 0 of an Asynchronous Event Request and reads the log page to clear the event
+    fn decode_async_event(&mut self, dw0: u32) -> Result<AsyncEvent, Box<dyn Error>> {
+        let event_type = (dw0 & 0x7) as u8;
+        let info = ((dw0 >> 8) & 0xFF) as u8;
+        let lid = ((dw0 >> 16) & 0xFF) as u8;
+
+        let event = match (event_type, info) {
+            (0, _) => {
+                self.error_log()?;
+                return Ok(AsyncEvent::Error { info });
+            }
+            (1, 0) => AsyncEvent::ReliabilityDegraded,
+            (1, 1) => AsyncEvent::TemperatureThreshold,
+            (1, 2) => AsyncEvent::SpareBelowThreshold,
+            (1, _) => AsyncEvent::SmartHealth { info },
+            (2, 0) => return Ok(AsyncEvent::NamespaceAttributeChanged(self.changed_namespace_list()?)),
+            (2, 1) => AsyncEvent::FirmwareActivationStarting,
+            (2, 2) => AsyncEvent::TelemetryLogChanged,
+            (2, _) => AsyncEvent::Notice { info },
+            (6, _) => AsyncEvent::IoCommandSpecific { info },
+            (7, _) => AsyncEvent::VendorSpecific { info },
+            _ => AsyncEvent::Other { event_type, info },
+        };
+
+        let mut log = [0u8; 512];
+        self.get_log_page(lid, 0xFFFF_FFFF, 0, &mut log)?;
+        Ok(event)
+    }
+
+    fn submit_async_event_request(&mut self, c_id: u16) {
+        let tail = self.admin_sq.submit(NvmeCommand::async_event_req(c_id));
+        self.write_reg_idx(NvmeArrayRegs::SQyTDBL, 0, tail as u32);
+        self.aer_outstanding.insert(c_id);
+    }
+
+    pub fn clear_namespace(&mut self, ns_id: Option<u32>) {
+        let ns_id = if let Some(ns_id) = ns_id {
+            assert!(self.namespaces.contains_key(&ns_id));
+            ns_id
+        } else {
+            0xFFFF_FFFF
+        };
+        self.submit_and_complete_admin(|c_id, _| NvmeCommand::format_nvm(c_id, ns_id, 0, false, 0, false, 1));
+    }
+
+    /// Formats namespace `ns_id` (or all namespaces for `0xFFFF_FFFF`) and re-reads it afterwards
+    pub fn format(&mut self, ns_id: u32, options: FormatOptions) -> Result<(), Box<dyn Error>> {
+        // OACS bit 1: Format NVM command
+        if self.ctrl.oacs & (1 << 1) == 0 {
+            return Err("Format NVM is not supported by the controller".into());
+        }
+        if options.pi_type > 3 {
+            return Err("Invalid protection information type".into());
+        }
+
+        let identify_id = if ns_id == 0xFFFF_FFFF {
+            *self.namespaces.keys().next().ok_or("No namespaces to format")?
+        } else if self.namespaces.contains_key(&ns_id) {
+            ns_id
+        } else {
+            return Err("Namespace not found".into());
+        };
+        self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::identify_namespace(c_id, addr, identify_id)
+        })?;
+        let namespace_data: IdentifyNamespaceData =
+            unsafe { *(self.buffer.virt as *const IdentifyNamespaceData) };
+        let lba_format_support = namespace_data.lba_format_support;
+        if options.lba_format > namespace_data.nlbaf || options.lba_format > 0xF
+            || (lba_format_support[options.lba_format as usize] >> 16) & 0xFF == 0 {
+            return Err("Unsupported LBA format".into());
+        }
+
+        println!("Formatting namespace {ns_id} with LBA format {}", options.lba_format);
+        self.submit_and_complete_admin_timeout(
+            |c_id, _| {
+                NvmeCommand::format_nvm(
+                    c_id,
+                    ns_id,
+                    options.lba_format,
+                    options.extended_metadata,
+                    options.pi_type,
+                    options.pi_first,
+                    options.secure_erase as u8,
+                )
+            },
+            options.timeout,
+        )?;
+
+        if ns_id == 0xFFFF_FFFF {
+            self.refresh_namespaces();
+        } else {
+            let zns = self.namespaces[&ns_id].zns_info.is_some();
+            self.identify_namespace(ns_id);
+            if zns {
+                self.identify_zns_namespace(ns_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Atomically compares `compare` with the blocks at `lba` and writes `data` if they match.
+    /// Returns `Ok(false)` if the compare failed and the write was aborted.
+    pub fn compare_and_write(
+        &mut self,
+        ns_id: u32,
+        compare: &impl DmaSlice,
+        data: &impl DmaSlice,
+        lba: u64,
+    ) -> Result<bool, Box<dyn Error>> {
+        let ns = *self.namespaces.get(&ns_id).ok_or("Namespace not found")?;
+        let acwu = self.compare_and_write_unit()?;
+        if self.io_sq.free_slots() < 2 {
+            return Err("queue full".into());
+        }
+        let (mut compare, mut write) = fused_compare_write(ns_id, ns.block_size, compare, data, lba, acwu)?;
+
+        let q_id = 1;
+        compare.c_id = self.io_sq.tail as u16;
+        self.io_sq.submit(compare);
+        write.c_id = self.io_sq.tail as u16;
+        let tail = self.io_sq.submit(write);
+        self.stats.submissions += 2;
+        self.write_reg_idx(NvmeArrayRegs::SQyTDBL, q_id as u16, tail as u32);
+
+        let (_, first, _) = self.io_cq.complete_spin();
+        let (head, second, _) = self.io_cq.complete_spin();
+        self.write_reg_idx(NvmeArrayRegs::CQyHDBL, q_id as u16, head as u32);
+        self.io_sq.head = second.sq_head as usize;
+        self.stats.completions += 2;
+        fused_compare_write_status([first, second])
+    }
+
+    /// Returns the atomic compare and write unit in blocks,
+    /// or an error if the controller doesn't support fused Compare and Write
+    fn compare_and_write_unit(&self) -> Result<u64, Box<dyn Error>> {
+        // ONCS bit 0: Compare command, FUSES bit 0: Compare and Write fused operation
+        if self.ctrl.oncs & 1 == 0 || self.ctrl.fuses & 1 == 0 {
+            return Err("Compare and Write is not supported by the controller".into());
+        }
+        Ok(self.ctrl.acwu as u64 + 1)
+    }
+
+    // Unfortunately not supported by the WD ZNS SSD :(
+    pub fn copy(&mut self, ns_id: u32, src: u64, dest: u64, len: u64) -> Result<(), Box<dyn Error>> {
+        self.copy_ranges(ns_id, &[(src, len)], dest)
+    }
+
+    /// Copies the source ranges, given as (slba, blocks), to consecutive blocks starting at `dest`.
+    /// Ranges are split and packed into as few copy commands as MSSRL, MCL and MSRC allow.
+    pub fn copy_ranges(&mut self, ns_id: u32, ranges: &[(u64, u64)], mut dest: u64) -> Result<(), Box<dyn Error>> {
+        // ONCS bit 8: Copy command
+        if self.ctrl.oncs & (1 << 8) == 0 {
+            return Err("Copy command is not supported by the controller".into());
+        }
+        let ns = *self.namespaces.get(&ns_id).ok_or("Namespace not found")?;
+
+        for ranges in pack_copy_ranges(&ns, ranges) {
+            self.submit_copy(ns_id, &ranges, dest)?;
+            dest += ranges.iter().map(|&(_, blocks)| blocks).sum::<u64>();
+        }
+
+        Ok(())
+    }
+
+    fn submit_copy(&mut self, ns_id: u32, ranges: &[(u64, u64)], dest: u64) -> Result<(), Box<dyn Error>> {
+        write_copy_ranges(self.buffer.virt as *mut SourceRangeEntriesDescriptorFormat0, ranges);
+        let bytes = (ranges.len() * COPY_RANGE_SIZE) as u64;
+        let ptr0 = self.buffer.phys as u64;
+        let ptr1 = self.get_prp2(bytes, ptr0);
+
+        let q_id = 1;
+        let entry = NvmeCommand::copy(self.io_sq.tail as u16, ns_id, dest, (ranges.len() - 1) as u8, ptr0, ptr1);
+        let tail = self.io_sq.submit(entry);
+        self.stats.submissions += 1;
+        self.write_reg_idx(NvmeArrayRegs::SQyTDBL, q_id as u16, tail as u32);
+        let completion = self.complete_io(1).map_err(|_| "Copy command failed")?;
+        self.io_sq.head = completion.sq_head as usize;
+
+        Ok(())
+    }
+
+    /// Registers, unregisters or replaces the reservation key of this host on namespace `ns_id`.
+    /// `ignore_key` skips checking `current_key`, e.g. to re-register after losing the key.
+    /// `ptpl` sets whether reservations persist through power loss, `None` keeps the current setting.
+    pub fn reservation_register(
+        &mut self,
+        ns_id: u32,
+        action: ReservationRegisterAction,
+        current_key: u64,
+        new_key: u64,
+        ignore_key: bool,
+        ptpl: Option<bool>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.buffer[..8].copy_from_slice(&current_key.to_le_bytes());
+        self.buffer[8..16].copy_from_slice(&new_key.to_le_bytes());
+        let cptpl = reservation_cptpl(ptpl);
+        self.reservation_io(|c_id, ptr| {
+            NvmeCommand::reservation_register(c_id, ns_id, ptr, action as u8, ignore_key, cptpl)
+        })
+    }
+
+    /// Acquires a reservation of type `rtype` with `key`, or preempts the registrant holding `preempt_key`
+    pub fn reservation_acquire(
+        &mut self,
+        ns_id: u32,
+        action: ReservationAcquireAction,
+        rtype: ReservationType,
+        key: u64,
+        preempt_key: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        self.buffer[..8].copy_from_slice(&key.to_le_bytes());
+        self.buffer[8..16].copy_from_slice(&preempt_key.to_le_bytes());
+        self.reservation_io(|c_id, ptr| {
+            NvmeCommand::reservation_acquire(c_id, ns_id, ptr, action as u8, false, rtype as u8)
+        })
+    }
+
+    /// Releases the reservation of type `rtype` held with `key`, or clears all reservations and registrations
+    pub fn reservation_release(
+        &mut self,
+        ns_id: u32,
+        action: ReservationReleaseAction,
+        rtype: ReservationType,
+        key: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        self.buffer[..8].copy_from_slice(&key.to_le_bytes());
+        self.reservation_io(|c_id, ptr| {
+            NvmeCommand::reservation_release(c_id, ns_id, ptr, action as u8, false, rtype as u8)
+        })
+    }
+
+    /// Returns the reservation holder and the registered hosts of namespace `ns_id`
+    pub fn reservation_report(&mut self, ns_id: u32) -> Result<ReservationStatus, Box<dyn Error>> {
+        let numd = (RESERVATION_REPORT_SIZE / 4 - 1) as u32;
+        let extended = self.ctrl.ctratt & 1 == 1;
+        self.reservation_io(|c_id, ptr| {
+            NvmeCommand::reservation_report(c_id, ns_id, ptr, 0, numd, extended)
+        })?;
+        Ok(ReservationStatus::parse(&self.buffer[..RESERVATION_REPORT_SIZE], extended))
+    }
+
+    /// Returns the directive types supported and enabled on namespace `ns_id`
+    pub fn directive_support(&mut self, ns_id: u32) -> Result<DirectiveSupport, Box<dyn Error>> {
+        self.check_directive_support()?;
+        self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::directive_receive(c_id, ns_id, addr, 4096 / 4 - 1, IDENTIFY_RETURN_PARAMETERS, DTYPE_IDENTIFY, 0)
+        })?;
+        Ok(DirectiveSupport::parse(&self.buffer[..96]))
+    }
+
+    /// Enables (or disables) the streams directive on namespace `ns_id`, needed before writes can carry a stream id
+    pub fn enable_streams(&mut self, ns_id: u32, enable: bool) -> Result<(), Box<dyn Error>> {
+        if !self.directive_support(ns_id)?.streams_supported() {
+            return Err("Streams directive is not supported by the controller".into());
+        }
+        let cdw12 = ((DTYPE_STREAMS as u32) << 8) | enable as u32;
+        self.submit_and_complete_admin(|c_id, _| {
+            NvmeCommand::directive_send(c_id, ns_id, IDENTIFY_ENABLE_DIRECTIVE, DTYPE_IDENTIFY, 0, cdw12)
+        })?;
+        Ok(())
+    }
+
+    /// Returns the stream limits of the subsystem and the streams allocated to namespace `ns_id`
+    pub fn stream_parameters(&mut self, ns_id: u32) -> Result<StreamParameters, Box<dyn Error>> {
+        self.check_directive_support()?;
+        self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::directive_receive(c_id, ns_id, addr, 32 / 4 - 1, STREAMS_RETURN_PARAMETERS, DTYPE_STREAMS, 0)
+        })?;
+        Ok(StreamParameters::parse(&self.buffer[..32]))
+    }
+
+    /// Allocates `count` streams exclusively to namespace `ns_id`, returns the number actually allocated
+    pub fn allocate_streams(&mut self, ns_id: u32, count: u16) -> Result<u16, Box<dyn Error>> {
+        self.check_directive_support()?;
+        let entry = self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::directive_receive(c_id, ns_id, addr, 0, STREAMS_ALLOCATE_RESOURCES, DTYPE_STREAMS, count as u32)
+        })?;
+        Ok(entry.command_specific1 as u16)
+    }
+
+    /// Returns the ids of the open streams of namespace `ns_id`
+    pub fn open_streams(&mut self, ns_id: u32) -> Result<Vec<u16>, Box<dyn Error>> {
+        self.check_directive_support()?;
+        self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::directive_receive(c_id, ns_id, addr, 4096 / 4 - 1, STREAMS_GET_STATUS, DTYPE_STREAMS, 0)
+        })?;
+        // Open stream count followed by the stream ids, only the ones fitting into a page are returned
+        let data = &self.buffer[..4096];
+        let count = u16::from_le_bytes([data[0], data[1]]) as usize;
+        Ok(data[2..]
+            .chunks_exact(2)
+            .take(count)
+            .map(|id| u16::from_le_bytes([id[0], id[1]]))
+            .collect())
+    }
+
+    /// Closes stream `stream_id` of namespace `ns_id`, the id can be reused afterwards
+    pub fn release_stream(&mut self, ns_id: u32, stream_id: u16) -> Result<(), Box<dyn Error>> {
+        self.check_directive_support()?;
+        self.submit_and_complete_admin(|c_id, _| {
+            NvmeCommand::directive_send(c_id, ns_id, STREAMS_RELEASE_IDENTIFIER, DTYPE_STREAMS, stream_id, 0)
+        })?;
+        Ok(())
+    }
+
+    /// Releases all streams allocated to namespace `ns_id`
+    pub fn release_streams(&mut self, ns_id: u32) -> Result<(), Box<dyn Error>> {
+        self.check_directive_support()?;
+        self.submit_and_complete_admin(|c_id, _| {
+            NvmeCommand::directive_send(c_id, ns_id, STREAMS_RELEASE_RESOURCES, DTYPE_STREAMS, 0, 0)
+        })?;
+        Ok(())
+    }
+
+    fn check_directive_support(&self) -> Result<(), Box<dyn Error>> {
+        // OACS bit 5: Directive Send and Directive Receive
+        if self.ctrl.oacs & (1 << 5) == 0 {
+            return Err("Directives are not supported by the controller".into());
+        }
+        Ok(())
+    }
+
+    /// Returns the FDP configurations of the endurance group of namespace `ns_id`
+    pub fn fdp_configurations(&mut self, ns_id: u32) -> Result<Vec<FdpConfiguration>, Box<dyn Error>> {
+        let endgid = self.fdp_endurance_group(ns_id)?;
+        let mut header = [0u8; 16];
+        self.get_log_page_scoped(LID_FDP_CONFIGURATIONS, 0, 0, endgid, 0, &mut header)?;
+        let size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+
+        let mut data = vec![0u8; size.max(16).next_multiple_of(4)];
+        self.get_log_page_scoped(LID_FDP_CONFIGURATIONS, 0, 0, endgid, 0, &mut data)?;
+        Ok(FdpConfiguration::parse_all(&data))
+    }
+
+    /// Returns the index of the FDP configuration enabled on the endurance group of namespace `ns_id`,
+    /// or `None` if FDP is disabled
+    pub fn fdp_configuration_index(&mut self, ns_id: u32) -> Result<Option<u8>, Box<dyn Error>> {
+        let endgid = self.fdp_endurance_group(ns_id)?;
+        let entry = self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::get_features(c_id, addr, FID_FLEXIBLE_DATA_PLACEMENT, 0, endgid as u32)
+        })?;
+        let dw0 = entry.command_specific1;
+        Ok((dw0 & 1 == 1).then_some((dw0 >> 8) as u8))
+    }
+
+    /// Returns who places data through each reclaim unit handle of the endurance group of namespace `ns_id`
+    pub fn reclaim_unit_handle_usage(&mut self, ns_id: u32) -> Result<Vec<ReclaimUnitHandleUsage>, Box<dyn Error>> {
+        let endgid = self.fdp_endurance_group(ns_id)?;
+        let mut header = [0u8; 8];
+        self.get_log_page_scoped(LID_RECLAIM_UNIT_HANDLE_USAGE, 0, 0, endgid, 0, &mut header)?;
+        let n_handles = u16::from_le_bytes([header[0], header[1]]) as usize;
+
+        let mut data = vec![0u8; 8 + n_handles * 8];
+        self.get_log_page_scoped(LID_RECLAIM_UNIT_HANDLE_USAGE, 0, 0, endgid, 0, &mut data)?;
+        Ok(data[8..].chunks_exact(8).map(|ruh| ReclaimUnitHandleUsage::from(ruh[0])).collect())
+    }
+
+    /// Reads the FDP Statistics log page of the endurance group of namespace `ns_id`
+    pub fn fdp_statistics(&mut self, ns_id: u32) -> Result<FdpStatistics, Box<dyn Error>> {
+        let endgid = self.fdp_endurance_group(ns_id)?;
+        let mut data = [0u8; std::mem::size_of::<FdpStatistics>()];
+        self.get_log_page_scoped(LID_FDP_STATISTICS, 0, 0, endgid, 0, &mut data)?;
+        Ok(unsafe { std::ptr::read_unaligned(data.as_ptr() as *const FdpStatistics) })
+    }
+
+    /// Reads the FDP events of the endurance group of namespace `ns_id`,
+    /// the ones caused by the controller instead of the host if `controller_events` is set
+    pub fn fdp_events(&mut self, ns_id: u32, controller_events: bool) -> Result<Vec<FdpEvent>, Box<dyn Error>> {
+        let endgid = self.fdp_endurance_group(ns_id)?;
+        let lsp = controller_events as u8;
+        let mut header = [0u8; 64];
+        self.get_log_page_scoped(LID_FDP_EVENTS, 0, lsp, endgid, 0, &mut header)?;
+        let n_events = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+
+        let mut data = vec![0u8; 64 + n_events * 64];
+        self.get_log_page_scoped(LID_FDP_EVENTS, 0, lsp, endgid, 0, &mut data)?;
+        Ok(data[64..].chunks_exact(64).map(FdpEvent::parse).collect())
+    }
+
+    /// Returns the state of the active reclaim unit of each placement handle of namespace `ns_id`
+    pub fn reclaim_unit_handle_status(&mut self, ns_id: u32) -> Result<Vec<ReclaimUnitHandleStatus>, Box<dyn Error>> {
+        self.fdp_endurance_group(ns_id)?;
+        let numd = (4096 / 4 - 1) as u32;
+        self.submit_and_complete_io(
+            |c_id, ptr| NvmeCommand::io_management_receive(c_id, ns_id, ptr, 0, numd, MO_RECLAIM_UNIT_HANDLE_STATUS, 0),
+            "Reclaim unit handle status failed",
+        )?;
+        // Descriptors that don't fit into a page are dropped
+        let data = &self.buffer[..4096];
+        // NRUHSD, bytes 0 to 13 are reserved
+        let n_descriptors = u16::from_le_bytes([data[14], data[15]]) as usize;
+        Ok(data[16..]
+            .chunks_exact(32)
+            .take(n_descriptors)
+            .map(ReclaimUnitHandleStatus::parse)
+            .collect())
+    }
+
+    /// Moves the placement identifiers `placement_ids` of namespace `ns_id` to new, empty reclaim units
+    pub fn update_reclaim_unit_handles(&mut self, ns_id: u32, placement_ids: &[u16]) -> Result<(), Box<dyn Error>> {
+        self.fdp_endurance_group(ns_id)?;
+        if placement_ids.is_empty() || placement_ids.len() > 0x1_0000 {
+            return Err("Reclaim unit handle update needs 1 to 65536 placement identifiers".into());
+        }
+        for (dest, id) in self.buffer[..placement_ids.len() * 2].chunks_exact_mut(2).zip(placement_ids) {
+            dest.copy_from_slice(&id.to_le_bytes());
+        }
+        let mos = (placement_ids.len() - 1) as u16;
+        let ptr1 = self.get_prp2(placement_ids.len() as u64 * 2, self.buffer.phys as u64);
+        self.submit_and_complete_io(
+            |c_id, ptr| NvmeCommand::io_management_send(c_id, ns_id, ptr, ptr1, MO_RECLAIM_UNIT_HANDLE_UPDATE, mos),
+            "Reclaim unit handle update failed",
+        )
+    }
+
+    // Returns the endurance group FDP log pages and features of namespace `ns_id` are scoped to
+    fn fdp_endurance_group(&self, ns_id: u32) -> Result<u16, Box<dyn Error>> {
+        // CTRATT bit 19: Flexible Data Placement
+        if self.ctrl.ctratt & (1 << 19) == 0 {
+            return Err("Flexible Data Placement is not supported by the controller".into());
+        }
+        Ok(self.namespaces.get(&ns_id).ok_or("Namespace not found")?.endgid)
+    }
+
+    /// Fences the failed peer registered with `peer_key`: removes its registration, aborts its outstanding
+    /// commands and takes over its reservation as type `rtype` if it held one.
+    /// The peer's subsequent I/O fails with `ReservationConflict`.
+    pub fn fence(&mut self, ns_id: u32, rtype: ReservationType, key: u64, peer_key: u64) -> Result<(), Box<dyn Error>> {
+        self.reservation_acquire(ns_id, ReservationAcquireAction::PreemptAndAbort, rtype, key, peer_key)
+    }
+
+    fn reservation_io<F: FnOnce(u16, u64) -> NvmeCommand>(&mut self, cmd_init: F) -> Result<(), Box<dyn Error>> {
+        // ONCS bit 5: Reservations
+        if self.ctrl.oncs & (1 << 5) == 0 {
+            return Err("Reservations are not supported by the controller".into());
+        }
+        self.submit_and_complete_io(cmd_init, "Reservation command failed")
+    }
+
+    // Submits a single command using the buffer on the I/O queue and waits for it
+    fn submit_and_complete_io<F: FnOnce(u16, u64) -> NvmeCommand>(
+        &mut self,
+        cmd_init: F,
+        msg: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let q_id = 1;
+        let entry = cmd_init(self.io_sq.tail as u16, self.buffer.phys as u64);
+        let tail = self.io_sq.submit(entry);
+        self.stats.submissions += 1;
+        self.write_reg_idx(NvmeArrayRegs::SQyTDBL, q_id as u16, tail as u32);
+        match self.complete_io(1) {
+            Ok(completion) => {
+                self.io_sq.head = completion.sq_head as usize;
+                Ok(())
+            }
+            Err(completion) => {
+                self.io_sq.head = completion.sq_head as usize;
+                Err(io_error(completion, msg))
+            }
+        }
+    }
+
     // ZNS specific commands
 
     // Zone Report Data Structure
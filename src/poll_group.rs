@@ -0,0 +1,279 @@
+// Reactor-style polling: one thread drives the completion queues of many queue pairs,
+// possibly of different devices, and dispatches a callback per finished submission
+
+use crate::cmd::NvmeCommand;
+use crate::memory::DmaSlice;
+use crate::nvme::NvmeQueuePair;
+use crate::queues::{c_id_slot, NvmeCompletion};
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+/// Called once all commands of a submission completed, with the last completion or the first failed one.
+/// The group is passed in so the callback can submit follow-up I/O.
+pub type CompletionCallback = Box<dyn FnOnce(&mut PollGroup, Result<NvmeCompletion, NvmeCompletion>) + Send>;
+
+// Upper bound of completions reaped from one queue per poll, so a busy queue can't starve the others
+const MAX_COMPLETIONS_PER_POLL: usize = 64;
+
+/// Identifies a queue pair added to a `PollGroup`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QueueHandle(usize);
+
+// A submission waiting for its commands to complete
+struct PendingOp {
+    remaining: usize,
+    failed: Option<NvmeCompletion>,
+    callback: CompletionCallback,
+}
+
+struct GroupQueue {
+    qpair: NvmeQueuePair,
+    slots: Vec<Option<usize>>, // Pending op of each submission queue slot
+    ops: Vec<Option<PendingOp>>,
+    free_ops: Vec<usize>,
+    outstanding: usize, // Commands, not ops
+}
+
+impl GroupQueue {
+    // Records a finished command, returns the op's callback and result once its last command finished
+    fn complete(&mut self, c_entry: NvmeCompletion) -> Option<(CompletionCallback, Result<NvmeCompletion, NvmeCompletion>)> {
+        let slot = c_id_slot(c_entry.c_id, self.slots.len());
+        let idx = self.slots.get_mut(slot)?.take()?;
+        self.outstanding -= 1;
+        let op = self.ops[idx].as_mut()?;
+        op.remaining -= 1;
+        if c_entry.status >> 1 != 0 && op.failed.is_none() {
+            op.failed = Some(c_entry);
+        }
+        if op.remaining > 0 {
+            return None;
+        }
+        let op = self.ops[idx].take()?;
+        self.free_ops.push(idx);
+        let result = match op.failed {
+            Some(failed) => Err(failed),
+            None => Ok(c_entry),
+        };
+        Some((op.callback, result))
+    }
+
+    fn add_op(&mut self, remaining: usize, callback: CompletionCallback) -> usize {
+        let op = PendingOp {
+            remaining,
+            failed: None,
+            callback,
+        };
+        match self.free_ops.pop() {
+            Some(idx) => {
+                self.ops[idx] = Some(op);
+                idx
+            }
+            None => {
+                self.ops.push(Some(op));
+                self.ops.len() - 1
+            }
+        }
+    }
+
+    // Command id of the next submission, the slot is recovered from its lower bits on completion
+    fn next_c_id(&self) -> u16 {
+        self.qpair.sub_queue.c_id(self.qpair.id)
+    }
+
+    fn submit(&mut self, entry: NvmeCommand, op: usize) {
+        let slot = c_id_slot(entry.c_id, self.slots.len());
+        self.slots[slot] = Some(op);
+        self.outstanding += 1;
+        // Free slots were checked for the whole op before its first command
+        self.qpair.submit_command(entry).expect("queue full");
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PollGroupStats {
+    pub polls: u64,
+    pub busy_polls: u64, // Polls that reaped at least one completion
+    pub completions: u64,
+    pub busy_time: Duration, // Spent in busy polls, including callbacks
+    pub idle_time: Duration,
+}
+
+impl PollGroupStats {
+    pub fn idle_polls(&self) -> u64 {
+        self.polls - self.busy_polls
+    }
+
+    /// Fraction of the polling time spent in polls that found work
+    pub fn utilization(&self) -> f64 {
+        let total = (self.busy_time + self.idle_time).as_secs_f64();
+        if total == 0.0 {
+            return 0.0;
+        }
+        self.busy_time.as_secs_f64() / total
+    }
+}
+
+/// Owns queue pairs and polls all of them from the calling thread
+#[derive(Default)]
+pub struct PollGroup {
+    queues: Vec<Option<GroupQueue>>,
+    stats: PollGroupStats,
+}
+
+impl PollGroup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a queue pair of any device, it must not have outstanding commands
+    pub fn add(&mut self, qpair: NvmeQueuePair) -> QueueHandle {
+        let queue = GroupQueue {
+            slots: vec![None; qpair.sub_queue.len()],
+            qpair,
+            ops: Vec::new(),
+            free_ops: Vec::new(),
+            outstanding: 0,
+        };
+        match self.queues.iter().position(Option::is_none) {
+            Some(idx) => {
+                self.queues[idx] = Some(queue);
+                QueueHandle(idx)
+            }
+            None => {
+                self.queues.push(Some(queue));
+                QueueHandle(self.queues.len() - 1)
+            }
+        }
+    }
+
+    /// Takes the queue pair out of the group, e.g. to delete it. Fails while it has outstanding commands.
+    pub fn remove(&mut self, handle: QueueHandle) -> Result<NvmeQueuePair, Box<dyn Error>> {
+        let queue = self.queues.get_mut(handle.0).ok_or("Unknown queue handle")?;
+        match queue {
+            Some(q) if q.outstanding > 0 => Err(format!("Queue pair has {} outstanding commands", q.outstanding).into()),
+            Some(_) => Ok(queue.take().unwrap().qpair),
+            None => Err("Unknown queue handle".into()),
+        }
+    }
+
+    fn queue(&mut self, handle: QueueHandle) -> Result<&mut GroupQueue, Box<dyn Error>> {
+        self.queues
+            .get_mut(handle.0)
+            .and_then(Option::as_mut)
+            .ok_or_else(|| "Unknown queue handle".into())
+    }
+
+    /// Queues a read or write of `data` at `lba`, `callback` runs from `poll` once it finished.
+    /// Like `NvmeQueuePair::submit_io`, the transfer is split into 8KiB commands, which are all submitted or none.
+    #[allow(clippy::too_many_arguments)]
+    pub fn submit_io(
+        &mut self,
+        handle: QueueHandle,
+        ns_id: u32,
+        block_size: u64,
+        data: &impl DmaSlice,
+        mut lba: u64,
+        write: bool,
+        callback: CompletionCallback,
+    ) -> Result<(), Box<dyn Error>> {
+        let queue = self.queue(handle)?;
+        let n_commands = data.chunks(2 * 4096).count();
+        if n_commands == 0 {
+            return Err("Empty I/O buffer".into());
+        }
+        if queue.qpair.sub_queue.free_slots() < n_commands {
+            return Err("queue full".into());
+        }
+
+        let op = queue.add_op(n_commands, callback);
+        for chunk in data.chunks(2 * 4096) {
+            let blocks = (chunk.slice.len() as u64).div_ceil(block_size);
+            let addr = chunk.phys_addr as u64;
+            let ptr1 = if blocks * block_size <= 4096 { 0 } else { addr + 4096 };
+            let c_id = queue.next_c_id();
+            let entry = if write {
+                NvmeCommand::io_write(c_id, ns_id, lba, blocks as u16 - 1, addr, ptr1)
+            } else {
+                NvmeCommand::io_read(c_id, ns_id, lba, blocks as u16 - 1, addr, ptr1)
+            };
+            queue.submit(entry, op);
+            lba += blocks;
+        }
+        Ok(())
+    }
+
+    /// Queues a single command built by `cmd_init` from its command id, e.g. a flush
+    pub fn submit<F: FnOnce(u16) -> NvmeCommand>(
+        &mut self,
+        handle: QueueHandle,
+        cmd_init: F,
+        callback: CompletionCallback,
+    ) -> Result<(), Box<dyn Error>> {
+        let queue = self.queue(handle)?;
+        if queue.qpair.sub_queue.free_slots() == 0 {
+            return Err("queue full".into());
+        }
+        let op = queue.add_op(1, callback);
+        let entry = cmd_init(queue.next_c_id());
+        queue.submit(entry, op);
+        Ok(())
+    }
+
+    /// Polls every queue pair once and runs the callbacks of finished submissions, returns the reaped completions
+    pub fn poll(&mut self) -> usize {
+        let start = Instant::now();
+        let mut finished = Vec::new();
+        let mut reaped = 0;
+        for queue in self.queues.iter_mut().flatten() {
+            if queue.outstanding == 0 {
+                continue;
+            }
+            let mut completions = Vec::new();
+            reaped += queue
+                .qpair
+                .poll_completions(MAX_COMPLETIONS_PER_POLL, |c_entry| completions.push(c_entry));
+            finished.extend(completions.into_iter().filter_map(|c_entry| queue.complete(c_entry)));
+        }
+        for (callback, result) in finished {
+            callback(self, result);
+        }
+
+        self.stats.polls += 1;
+        self.stats.completions += reaped as u64;
+        if reaped > 0 {
+            self.stats.busy_polls += 1;
+            self.stats.busy_time += start.elapsed();
+        } else {
+            self.stats.idle_time += start.elapsed();
+        }
+        reaped
+    }
+
+    /// Polls until no queue pair has outstanding commands, including ones submitted by callbacks
+    pub fn poll_until_idle(&mut self) {
+        while self.outstanding() > 0 {
+            self.poll();
+        }
+    }
+
+    /// Commands submitted through the group that didn't complete yet
+    pub fn outstanding(&self) -> usize {
+        self.queues.iter().flatten().map(|queue| queue.outstanding).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> PollGroupStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = PollGroupStats::default();
+    }
+}
@@ -0,0 +1,95 @@
+// Power management of the controller
+
+use std::error::Error;
+
+// Power State Descriptor Data Structure
+// See Figure 276 of the NVMe Base Specification 2.0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerStateDescriptor {
+    pub max_power: f32, // in Watts
+    pub non_operational: bool,
+    pub entry_latency: u32, // in microseconds
+    pub exit_latency: u32,  // in microseconds
+    pub relative_read_throughput: u8,
+    pub relative_read_latency: u8,
+    pub relative_write_throughput: u8,
+    pub relative_write_latency: u8,
+    pub idle_power: Option<f32>,   // in Watts, None if not reported
+    pub active_power: Option<f32>, // in Watts, None if not reported
+}
+
+impl PowerStateDescriptor {
+    pub(crate) fn parse(data: &[u8; 32]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        // Scales: 00b = not reported, 01b = 0.0001 W, 10b = 0.01 W
+        let scaled = |value: u16, scale: u8| match scale {
+            1 => Some(value as f32 * 0.0001),
+            2 => Some(value as f32 * 0.01),
+            _ => None,
+        };
+
+        let max_power_scale = if data[3] & 1 == 1 { 0.0001 } else { 0.01 };
+        Self {
+            max_power: u16_at(0) as f32 * max_power_scale,
+            non_operational: (data[3] >> 1) & 1 == 1,
+            entry_latency: u32_at(4),
+            exit_latency: u32_at(8),
+            relative_read_throughput: data[12] & 0x1F,
+            relative_read_latency: data[13] & 0x1F,
+            relative_write_throughput: data[14] & 0x1F,
+            relative_write_latency: data[15] & 0x1F,
+            idle_power: scaled(u16_at(16), data[18] >> 6),
+            active_power: scaled(u16_at(20), data[22] >> 6),
+        }
+    }
+}
+
+/// How the Autonomous Power State Transition table is built
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApstPolicy {
+    /// Non-operational states whose exit latency is at most `max_latency` microseconds form a chain:
+    /// every state transitions to the next deeper one of them, after an idle time of 50 times
+    /// that state's entry + exit latency
+    LatencyScaled { max_latency: u32 },
+    /// Every state shallower than `target_state` transitions to it after `idle_time` milliseconds
+    Fixed { target_state: u8, idle_time: u32 },
+}
+
+// Idle Time Prior to Transition is a 24 bit field in milliseconds
+const APST_MAX_IDLE_TIME: u64 = (1 << 24) - 1;
+
+impl ApstPolicy {
+    /// Builds the APST table for the given power states, fails if a fixed target isn't a non-operational state
+    pub fn table(&self, states: &[PowerStateDescriptor]) -> Result<[u64; 32], Box<dyn Error>> {
+        let mut table = [0u64; 32];
+        let entry = |state: usize, idle_time: u64| ((idle_time.min(APST_MAX_IDLE_TIME)) << 8) | ((state as u64) << 3);
+
+        match *self {
+            ApstPolicy::LatencyScaled { max_latency } => {
+                let mut target = None;
+                for (state, descriptor) in states.iter().enumerate().rev() {
+                    if let Some(target) = target {
+                        table[state] = target;
+                    }
+                    if !descriptor.non_operational || descriptor.exit_latency > max_latency {
+                        continue;
+                    }
+                    let total_latency = descriptor.entry_latency as u64 + descriptor.exit_latency as u64;
+                    target = Some(entry(state, total_latency.div_ceil(20)));
+                }
+            }
+            ApstPolicy::Fixed { target_state, idle_time } => {
+                match states.get(target_state as usize) {
+                    Some(descriptor) if descriptor.non_operational => {}
+                    Some(_) => return Err(format!("Power state {target_state} is operational").into()),
+                    None => return Err(format!("Power state {target_state} doesn't exist").into()),
+                }
+                for slot in table.iter_mut().take(target_state as usize) {
+                    *slot = entry(target_state as usize, idle_time as u64);
+                }
+            }
+        }
+        Ok(table)
+    }
+}
@@ -0,0 +1,450 @@
+// Host-side I/O QoS: token bucket limits on IOPS and bandwidth per tenant and per queue pair,
+// requests over the limit are deferred in order and dispatched once their budget refilled
+
+use crate::memory::{Dma, DmaSlice};
+use crate::nvme::NvmeQueuePair;
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+pub type TenantId = u32;
+
+/// Sustained rate per second and burst allowance, both in operations or bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub rate: u64,
+    pub burst: u64, // Budget that can be spent at once after being idle
+}
+
+/// Limits of a tenant or queue pair, unset limits don't throttle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QosLimits {
+    pub read_iops: Option<RateLimit>,
+    pub write_iops: Option<RateLimit>,
+    pub read_bandwidth: Option<RateLimit>, // Bytes per second
+    pub write_bandwidth: Option<RateLimit>,
+}
+
+impl QosLimits {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64, // Negative while paying off a request larger than the burst
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate as f64).min(self.limit.burst as f64);
+        self.refilled = now;
+    }
+
+    // Requests larger than the burst only need a full bucket, the excess is paid off afterwards
+    fn admits(&self, cost: u64) -> bool {
+        self.tokens >= cost.min(self.limit.burst) as f64
+    }
+
+    fn take(&mut self, cost: u64) {
+        self.tokens -= cost as f64;
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    read_iops: Option<TokenBucket>,
+    write_iops: Option<TokenBucket>,
+    read_bandwidth: Option<TokenBucket>,
+    write_bandwidth: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn new(limits: &QosLimits) -> Self {
+        let now = Instant::now();
+        let bucket = |limit: Option<RateLimit>| limit.map(|limit| TokenBucket::new(limit, now));
+        Self {
+            read_iops: bucket(limits.read_iops),
+            write_iops: bucket(limits.write_iops),
+            read_bandwidth: bucket(limits.read_bandwidth),
+            write_bandwidth: bucket(limits.write_bandwidth),
+        }
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.read_iops.is_none()
+            && self.write_iops.is_none()
+            && self.read_bandwidth.is_none()
+            && self.write_bandwidth.is_none()
+    }
+
+    fn for_direction(&mut self, write: bool) -> [&mut Option<TokenBucket>; 2] {
+        if write {
+            [&mut self.write_iops, &mut self.write_bandwidth]
+        } else {
+            [&mut self.read_iops, &mut self.read_bandwidth]
+        }
+    }
+
+    fn admits(&mut self, write: bool, bytes: u64, now: Instant) -> bool {
+        let [iops, bandwidth] = self.for_direction(write);
+        [(iops, 1), (bandwidth, bytes)].into_iter().all(|(bucket, cost)| match bucket {
+            Some(bucket) => {
+                bucket.refill(now);
+                bucket.admits(cost)
+            }
+            None => true,
+        })
+    }
+
+    fn take(&mut self, write: bool, bytes: u64) {
+        let [iops, bandwidth] = self.for_direction(write);
+        if let Some(bucket) = iops {
+            bucket.take(1);
+        }
+        if let Some(bucket) = bandwidth {
+            bucket.take(bytes);
+        }
+    }
+}
+
+/// Throttling statistics of a tenant
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QosStats {
+    pub submitted: u64, // Requests, each possibly split into several commands
+    pub submitted_bytes: u64,
+    pub throttled: u64, // Requests that had to wait for their budget
+    pub queue_full: u64, // Requests within their budget that had to wait for room in the submission queue
+    pub ordered: u64, // Requests within their budget deferred to stay behind an earlier deferred request
+    pub throttled_time: Duration, // Total time requests spent deferred
+    pub pending: usize, // Requests currently deferred
+}
+
+// A deferred request, it only refers to the caller's buffer, which needs to stay untouched until it completed
+struct Deferred {
+    ns_id: u32,
+    block_size: u64,
+    data: Dma<u8>,
+    lba: u64,
+    write: bool,
+    since: Instant,
+}
+
+struct Tenant {
+    buckets: Buckets,
+    pending: VecDeque<Deferred>,
+    stats: QosStats,
+}
+
+impl Tenant {
+    fn new(limits: &QosLimits) -> Self {
+        Self {
+            buckets: Buckets::new(limits),
+            pending: VecDeque::new(),
+            stats: QosStats::default(),
+        }
+    }
+}
+
+/// Outcome of `QosQueuePair::submit_io`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QosSubmission {
+    /// Submitted right away as this many commands, to be completed on the queue pair
+    Submitted(usize),
+    /// Over the limit, it is submitted by a later `dispatch`
+    Deferred,
+}
+
+/// Queue pair whose submissions are limited per tenant and in total.
+/// Without any limits configured, requests go straight to the queue pair.
+pub struct QosQueuePair {
+    qpair: NvmeQueuePair,
+    queue: Buckets, // Limits of the queue pair, on top of those of the tenants
+    tenants: BTreeMap<TenantId, Tenant>,
+    pending: usize,
+    next_tenant: TenantId, // Round robin position of dispatch
+}
+
+// Commands a request is split into by NvmeQueuePair::submit_io
+fn commands(data: &Dma<u8>) -> usize {
+    data.size.div_ceil(2 * 4096)
+}
+
+impl QosQueuePair {
+    pub fn new(qpair: NvmeQueuePair, limits: QosLimits) -> Self {
+        Self {
+            qpair,
+            queue: Buckets::new(&limits),
+            tenants: BTreeMap::new(),
+            pending: 0,
+            next_tenant: 0,
+        }
+    }
+
+    /// Sets the limits of `tenant`, tenants without limits set are unlimited
+    pub fn set_tenant_limits(&mut self, tenant: TenantId, limits: QosLimits) {
+        let buckets = Buckets::new(&limits);
+        self.tenants.entry(tenant).or_insert_with(|| Tenant::new(&limits)).buckets = buckets;
+    }
+
+    pub fn set_queue_limits(&mut self, limits: QosLimits) {
+        self.queue = Buckets::new(&limits);
+    }
+
+    /// The underlying queue pair, to complete the commands of submitted requests
+    pub fn queue_pair(&mut self) -> &mut NvmeQueuePair {
+        &mut self.qpair
+    }
+
+    /// Returns the queue pair, fails while requests are deferred
+    pub fn into_inner(self) -> Result<NvmeQueuePair, Box<dyn Error>> {
+        if self.pending > 0 {
+            return Err(format!("{} requests are still deferred", self.pending).into());
+        }
+        Ok(self.qpair)
+    }
+
+    /// Submits a read or write of `data` at `lba` for `tenant`, or defers it if that exceeds a limit
+    /// or the submission queue has no room. Deferred requests keep their order within a tenant.
+    /// A deferred request is not copied: `data` must not be reused or freed until `dispatch` submitted it
+    /// and its commands completed.
+    pub fn submit_io(
+        &mut self,
+        tenant: TenantId,
+        ns_id: u32,
+        block_size: u64,
+        data: &Dma<u8>,
+        lba: u64,
+        write: bool,
+    ) -> QosSubmission {
+        let tenant_state = self
+            .tenants
+            .entry(tenant)
+            .or_insert_with(|| Tenant::new(&QosLimits::default()));
+        let fits = self.qpair.sub_queue.free_slots() >= commands(data);
+
+        // Fast path without limits, no clock reads
+        let unlimited = tenant_state.buckets.is_unlimited() && self.queue.is_unlimited();
+        if fits && unlimited && tenant_state.pending.is_empty() {
+            let reqs = self.qpair.submit_io(ns_id, block_size, data, lba, write).commands;
+            tenant_state.stats.submitted += 1;
+            tenant_state.stats.submitted_bytes += data.size as u64;
+            return QosSubmission::Submitted(reqs);
+        }
+
+        let now = Instant::now();
+        let bytes = data.size as u64;
+        let within_limits =
+            tenant_state.buckets.admits(write, bytes, now) && self.queue.admits(write, bytes, now);
+        let in_order = tenant_state.pending.is_empty();
+        if fits && within_limits && in_order {
+            tenant_state.buckets.take(write, bytes);
+            self.queue.take(write, bytes);
+            let reqs = self.qpair.submit_io(ns_id, block_size, data, lba, write).commands;
+            tenant_state.stats.submitted += 1;
+            tenant_state.stats.submitted_bytes += bytes;
+            return QosSubmission::Submitted(reqs);
+        }
+
+        tenant_state.pending.push_back(Deferred {
+            ns_id,
+            block_size,
+            data: data.slice(0..data.size),
+            lba,
+            write,
+            since: now,
+        });
+        if !within_limits {
+            tenant_state.stats.throttled += 1;
+        } else if !in_order {
+            tenant_state.stats.ordered += 1;
+        } else {
+            tenant_state.stats.queue_full += 1;
+        }
+        tenant_state.stats.pending += 1;
+        self.pending += 1;
+        QosSubmission::Deferred
+    }
+
+    /// Submits deferred requests whose budget refilled, tenants take turns.
+    /// Returns the number of submitted commands, which need to be completed on the queue pair.
+    pub fn dispatch(&mut self) -> usize {
+        if self.pending == 0 {
+            return 0;
+        }
+        let now = Instant::now();
+        let mut reqs = 0;
+        let tenants: Vec<TenantId> = self
+            .tenants
+            .range(self.next_tenant..)
+            .chain(self.tenants.range(..self.next_tenant))
+            .filter(|(_, tenant)| !tenant.pending.is_empty())
+            .map(|(&id, _)| id)
+            .collect();
+
+        // One request per tenant and round, until no tenant can make progress
+        let mut progress = true;
+        while progress {
+            progress = false;
+            for &id in &tenants {
+                let tenant = self.tenants.get_mut(&id).unwrap();
+                let Some(request) = tenant.pending.front() else {
+                    continue;
+                };
+                let bytes = request.data.size as u64;
+                if self.qpair.sub_queue.free_slots() < commands(&request.data)
+                    || !tenant.buckets.admits(request.write, bytes, now)
+                    || !self.queue.admits(request.write, bytes, now)
+                {
+                    continue;
+                }
+                let request = tenant.pending.pop_front().unwrap();
+                tenant.buckets.take(request.write, bytes);
+                self.queue.take(request.write, bytes);
+                reqs += self.qpair.submit_io(
+                    request.ns_id,
+                    request.block_size,
+                    &request.data,
+                    request.lba,
+                    request.write,
+                )
+                .commands;
+                tenant.stats.submitted += 1;
+                tenant.stats.submitted_bytes += bytes;
+                tenant.stats.throttled_time += now.saturating_duration_since(request.since);
+                tenant.stats.pending -= 1;
+                self.pending -= 1;
+                self.next_tenant = id.wrapping_add(1);
+                progress = true;
+            }
+        }
+        reqs
+    }
+
+    /// Requests deferred over all tenants
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    pub fn stats(&self, tenant: TenantId) -> Option<QosStats> {
+        self.tenants.get(&tenant).map(|tenant| tenant.stats)
+    }
+
+    pub fn all_stats(&self) -> impl Iterator<Item = (TenantId, QosStats)> + '_ {
+        self.tenants.iter().map(|(&id, tenant)| (id, tenant.stats))
+    }
+
+    pub fn reset_stats(&mut self) {
+        for tenant in self.tenants.values_mut() {
+            tenant.stats = QosStats {
+                pending: tenant.pending.len(),
+                ..QosStats::default()
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit { rate: 1000, burst: 10 };
+
+    #[test]
+    fn bucket_starts_full_and_refills_at_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT, start);
+        assert!(bucket.admits(10));
+        bucket.take(10);
+        assert!(!bucket.admits(1));
+
+        bucket.refill(start + Duration::from_millis(5));
+        assert!(bucket.admits(4));
+        assert!(!bucket.admits(6));
+    }
+
+    #[test]
+    fn bucket_refill_is_capped_at_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT, start);
+        bucket.take(10);
+        bucket.refill(start + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 10.0);
+
+        // Time going backwards doesn't refill
+        bucket.refill(start);
+        assert_eq!(bucket.tokens, 10.0);
+    }
+
+    #[test]
+    fn bucket_admits_request_over_burst_and_pays_it_off() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT, start);
+        assert!(bucket.admits(25));
+        bucket.take(25);
+        assert_eq!(bucket.tokens, -15.0);
+
+        // Another large request has to wait for a full bucket again
+        bucket.refill(start + Duration::from_millis(10));
+        assert!(bucket.tokens < 0.0);
+        assert!(!bucket.admits(1));
+        assert!(!bucket.admits(25));
+
+        bucket.refill(start + Duration::from_millis(26));
+        assert!(bucket.admits(25));
+    }
+
+    #[test]
+    fn buckets_limit_each_direction_separately() {
+        let limits = QosLimits {
+            read_iops: Some(RateLimit { rate: 1, burst: 2 }),
+            ..QosLimits::default()
+        };
+        let mut buckets = Buckets::new(&limits);
+        assert!(!buckets.is_unlimited());
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            assert!(buckets.admits(false, 4096, now));
+            buckets.take(false, 4096);
+        }
+        assert!(!buckets.admits(false, 4096, now));
+        assert!(buckets.admits(true, 4096, now));
+    }
+
+    #[test]
+    fn buckets_need_both_iops_and_bandwidth() {
+        let limits = QosLimits {
+            write_iops: Some(RateLimit { rate: 1, burst: 100 }),
+            write_bandwidth: Some(RateLimit { rate: 1, burst: 8192 }),
+            ..QosLimits::default()
+        };
+        let mut buckets = Buckets::new(&limits);
+        let now = Instant::now();
+
+        assert!(buckets.admits(true, 8192, now));
+        buckets.take(true, 8192);
+        // Plenty of operations left, but no bytes
+        assert!(!buckets.admits(true, 1, now));
+        assert_eq!(buckets.write_iops.as_ref().unwrap().tokens, 99.0);
+    }
+
+    #[test]
+    fn unset_limits_are_unlimited() {
+        let mut buckets = Buckets::new(&QosLimits::default());
+        assert!(QosLimits::default().is_unlimited());
+        assert!(buckets.is_unlimited());
+        assert!(buckets.admits(true, u64::MAX, Instant::now()));
+    }
+}
@@ -1,5 +1,7 @@
 use crate::cmd::NvmeCommand;
 use crate::memory::*;
+use crate::stats::SharedQueueStats;
+use crate::trace::{SharedTrace, TraceEntry};
 use std::error::Error;
 use std::hint::spin_loop;
 
@@ -23,27 +25,63 @@ pub struct NvmeCompletion {
     pub status: u16,
 }
 
-/// maximum amount of submission entries on a 2MiB huge page
+/// Default queue length, the submission entries of a 2MiB huge page
 pub const QUEUE_LENGTH: usize = 1024;
 
+/// Largest queue the specification allows, CAP.MQES is a 0's based u16
+pub const MAX_QUEUE_LENGTH: usize = 65536;
+
+// Command ids of queues up to this length carry the queue id above the submission queue slot
+const TAGGED_QUEUE_LENGTH: usize = 2048;
+// Memory page size, CC.MPS is left at 0
+const PAGE_SIZE: usize = 4096;
+
+/// Submission queue slot a command id was derived from, see `NvmeSubQueue::c_id`
+pub(crate) fn c_id_slot(c_id: u16, len: usize) -> usize {
+    if len > TAGGED_QUEUE_LENGTH {
+        c_id as usize
+    } else {
+        c_id as usize % TAGGED_QUEUE_LENGTH
+    }
+}
+
+// Physical address of every memory page of the first `bytes` of `memory`.
+// Memory larger than a huge page is only virtually contiguous, so every huge page is translated on its own.
+fn memory_pages<T>(memory: &Dma<T>, bytes: usize) -> Result<Vec<u64>, Box<dyn Error>> {
+    let mut pages = Vec::with_capacity(bytes.div_ceil(PAGE_SIZE));
+    for offset in (0..bytes).step_by(HUGE_PAGE_SIZE) {
+        let phys = if offset == 0 {
+            memory.phys
+        } else {
+            virt_to_phys(memory.virt as usize + offset)?
+        };
+        let len = (bytes - offset).min(HUGE_PAGE_SIZE);
+        pages.extend((0..len).step_by(PAGE_SIZE).map(|page| (phys + page) as u64));
+    }
+    Ok(pages)
+}
+
 /// Submission queue
 pub struct NvmeSubQueue {
-    // TODO: switch to mempool for larger queue
-    commands: Dma<[NvmeCommand; QUEUE_LENGTH]>,
+    commands: Dma<NvmeCommand>, // len entries, spanning several huge pages for long queues
     pub head: usize,
     pub tail: usize,
     len: usize,
     pub doorbell: usize,
+    pub(crate) trace: Option<SharedTrace>,
+    pub(crate) stats: Option<SharedQueueStats>,
 }
 
 impl NvmeSubQueue {
     pub fn new(len: usize, doorbell: usize) -> Result<Self, Box<dyn Error>> {
         Ok(Self {
-            commands: Dma::allocate(crate::memory::HUGE_PAGE_SIZE)?,
+            commands: Dma::allocate(len.min(MAX_QUEUE_LENGTH) * std::mem::size_of::<NvmeCommand>())?,
             head: 0,
             tail: 0,
-            len: len.min(QUEUE_LENGTH),
+            len: len.min(MAX_QUEUE_LENGTH),
             doorbell,
+            trace: None,
+            stats: None,
         })
     }
 
@@ -51,10 +89,30 @@ impl NvmeSubQueue {
         self.head == self.tail
     }
 
+    /// Number of entries, one of them always stays unused
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Command id of the next submission on queue `q_id`, the slot is recovered with `c_id_slot`.
+    /// Queues longer than 2048 entries need all 16 bits for the slot.
+    pub(crate) fn c_id(&self, q_id: u16) -> u16 {
+        if self.len > TAGGED_QUEUE_LENGTH {
+            self.tail as u16
+        } else {
+            q_id << 11 | self.tail as u16
+        }
+    }
+
     pub fn is_full(&self) -> bool {
         self.head == (self.tail + 1) % self.len
     }
 
+    /// Number of entries that can still be submitted before the queue is full
+    pub fn free_slots(&self) -> usize {
+        (self.head + self.len - self.tail - 1) % self.len
+    }
+
     pub fn submit_checked(&mut self, entry: NvmeCommand) -> Option<usize> {
         if self.is_full() {
             None
@@ -65,8 +123,13 @@ impl NvmeSubQueue {
 
     #[inline(always)]
     pub fn submit(&mut self, entry: NvmeCommand) -> usize {
-        // println!("SUBMISSION ENTRY: {:?}", entry);
-        self.commands[self.tail] = entry;
+        if let Some(trace) = &self.trace {
+            trace.lock().unwrap().record(TraceEntry::Submission(entry));
+        }
+        if let Some(stats) = &self.stats {
+            stats.lock().unwrap().submitted(&entry);
+        }
+        unsafe { self.commands.virt.add(self.tail).write(entry) };
 
         self.tail = (self.tail + 1) % self.len;
         self.tail
@@ -75,32 +138,41 @@ impl NvmeSubQueue {
     pub fn get_addr(&self) -> usize {
         self.commands.phys
     }
+
+    /// Physical addresses of the memory pages holding the queue
+    pub(crate) fn pages(&self) -> Result<Vec<u64>, Box<dyn Error>> {
+        memory_pages(&self.commands, self.len * std::mem::size_of::<NvmeCommand>())
+    }
 }
 
 /// Completion queue
 pub struct NvmeCompQueue {
-    commands: Dma<[NvmeCompletion; QUEUE_LENGTH]>,
+    commands: Dma<NvmeCompletion>, // len entries
     head: usize,
     phase: bool,
     len: usize,
     pub doorbell: usize,
+    pub(crate) trace: Option<SharedTrace>,
+    pub(crate) stats: Option<SharedQueueStats>,
 }
 
 // TODO: error handling
 impl NvmeCompQueue {
     pub fn new(len: usize, doorbell: usize) -> Result<Self, Box<dyn Error>> {
         Ok(Self {
-            commands: Dma::allocate(crate::memory::HUGE_PAGE_SIZE)?,
+            commands: Dma::allocate(len.min(MAX_QUEUE_LENGTH) * std::mem::size_of::<NvmeCompletion>())?,
             head: 0,
             phase: true,
-            len: len.min(QUEUE_LENGTH),
+            len: len.min(MAX_QUEUE_LENGTH),
             doorbell,
+            trace: None,
+            stats: None,
         })
     }
 
     #[inline(always)]
     pub fn complete(&mut self) -> Option<(usize, NvmeCompletion, usize)> {
-        let entry = &self.commands[self.head];
+        let entry = unsafe { &*self.commands.virt.add(self.head) };
 
         if ((entry.status & 1) == 1) == self.phase {
             let prev = self.head;
@@ -108,7 +180,13 @@ impl NvmeCompQueue {
             if self.head == 0 {
                 self.phase = !self.phase;
             }
-            Some((self.head, entry.clone(), prev))
+            if let Some(trace) = &self.trace {
+                trace.lock().unwrap().record(TraceEntry::Completion(*entry));
+            }
+            if let Some(stats) = &self.stats {
+                stats.lock().unwrap().completed(entry);
+            }
+            Some((self.head, *entry, prev))
         } else {
             None
         }
@@ -118,6 +196,9 @@ impl NvmeCompQueue {
     #[inline(always)]
     pub fn complete_n(&mut self, commands: usize) -> (usize, NvmeCompletion, usize) {
         let prev = self.head;
+        // Only the last entry is polled, the skipped ones are traced and counted in order once it arrived
+        let trace = self.trace.take();
+        let stats = self.stats.take();
         self.head += commands - 1;
         if self.head >= self.len {
             self.phase = !self.phase;
@@ -125,6 +206,24 @@ impl NvmeCompQueue {
         self.head %= self.len;
 
         let (head, entry, _) = self.complete_spin();
+        if let Some(trace) = trace {
+            {
+                let mut trace = trace.lock().unwrap();
+                for i in 0..commands {
+                    trace.record(TraceEntry::Completion(*self.entry((prev + i) % self.len)));
+                }
+            }
+            self.trace = Some(trace);
+        }
+        if let Some(stats) = stats {
+            {
+                let mut stats = stats.lock().unwrap();
+                for i in 0..commands {
+                    stats.completed(self.entry((prev + i) % self.len));
+                }
+            }
+            self.stats = Some(stats);
+        }
         (head, entry, prev)
     }
 
@@ -141,4 +240,15 @@ impl NvmeCompQueue {
     pub fn get_addr(&self) -> usize {
         self.commands.phys
     }
+
+    /// Physical addresses of the memory pages holding the queue
+    pub(crate) fn pages(&self) -> Result<Vec<u64>, Box<dyn Error>> {
+        memory_pages(&self.commands, self.len * std::mem::size_of::<NvmeCompletion>())
+    }
+
+    // Entries are written by the controller, so they are read through the raw pointer
+    #[inline(always)]
+    fn entry(&self, idx: usize) -> &NvmeCompletion {
+        unsafe { &*self.commands.virt.add(idx) }
+    }
 }
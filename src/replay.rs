@@ -0,0 +1,690 @@
+// Replay of recorded I/O against a queue pair or a software backend, comparing the latencies
+// of the replay with the ones of the recording
+
+use crate::cmd::NvmeCommand;
+use crate::memory::{Dma, HUGE_PAGE_SIZE};
+use crate::nvme::NvmeQueuePair;
+use crate::trace::{TraceEntry, TraceRecord};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::hint::spin_loop;
+use std::time::{Duration, Instant};
+
+// Largest transfer of a single replayed command, larger ops are split
+const MAX_OP_BYTES: usize = 128 * 1024;
+const PAGE_SIZE: usize = 4096;
+const TAGS_PER_BUFFER: usize = HUGE_PAGE_SIZE / MAX_OP_BYTES;
+// Every tag owns one PRP list page, all lists share one huge page
+const MAX_TAGS: usize = HUGE_PAGE_SIZE / PAGE_SIZE;
+const SECTOR_SIZE: u64 = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayOpcode {
+    Read,
+    Write,
+    Flush,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayOp {
+    pub timestamp: Duration, // Submission time relative to the first op of the recording
+    pub ns_id: u32,
+    pub opcode: ReplayOpcode,
+    pub lba: u64,
+    pub blocks: u64,                        // 0 for flushes
+    pub recorded_latency: Option<Duration>, // None if the completion wasn't recorded
+}
+
+/// Read, write and flush commands of the I/O queues in a trace, with the recorded latency of
+/// commands whose completion is part of the trace
+pub fn ops_from_trace<I: IntoIterator<Item = TraceRecord>>(records: I) -> Vec<ReplayOp> {
+    let mut ops: Vec<ReplayOp> = Vec::new();
+    // (queue id, command id) -> (op index, submission timestamp)
+    let mut pending: HashMap<(u16, u16), (usize, u64)> = HashMap::new();
+    let mut start = None;
+    for record in records {
+        if record.q_id == 0 {
+            continue;
+        }
+        match record.entry {
+            TraceEntry::Submission(cmd) => {
+                let opcode = match cmd.opcode {
+                    0 => ReplayOpcode::Flush,
+                    1 => ReplayOpcode::Write,
+                    2 => ReplayOpcode::Read,
+                    _ => continue,
+                };
+                let (lba, blocks) = if opcode == ReplayOpcode::Flush {
+                    (0, 0)
+                } else {
+                    (
+                        cmd.cdw10 as u64 | (cmd.cdw11 as u64) << 32,
+                        (cmd.cdw12 & 0xFFFF) as u64 + 1,
+                    )
+                };
+                let start = *start.get_or_insert(record.timestamp);
+                pending.insert((record.q_id, cmd.c_id), (ops.len(), record.timestamp));
+                ops.push(ReplayOp {
+                    timestamp: Duration::from_nanos(record.timestamp.saturating_sub(start)),
+                    ns_id: cmd.ns_id,
+                    opcode,
+                    lba,
+                    blocks,
+                    recorded_latency: None,
+                });
+            }
+            TraceEntry::Completion(entry) => {
+                if let Some((idx, submitted)) = pending.remove(&(record.q_id, entry.c_id)) {
+                    ops[idx].recorded_latency = Some(Duration::from_nanos(record.timestamp.saturating_sub(submitted)));
+                }
+            }
+        }
+    }
+    ops
+}
+
+/// Parses a fio iolog of version 2 or 3, offsets and lengths are converted into blocks of `block_size`.
+/// fio doesn't log completions, so the ops carry no recorded latency.
+pub fn parse_fio_iolog(text: &str, ns_id: u32, block_size: u64) -> Result<Vec<ReplayOp>, Box<dyn Error>> {
+    if block_size == 0 {
+        return Err("Block size must not be 0".into());
+    }
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    let header = lines.next().ok_or("Empty iolog")?.trim();
+    let timestamped = match header {
+        "fio version 2 iolog" => false,
+        "fio version 3 iolog" => true,
+        _ => return Err(format!("Unsupported iolog header \"{header}\"").into()),
+    };
+
+    let mut ops = Vec::new();
+    // Version 2 only has wait actions, each relative to the previous one
+    let mut elapsed = Duration::ZERO;
+    for line in lines {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let number = |idx: usize| -> Result<u64, Box<dyn Error>> {
+            let field = fields.get(idx).ok_or_else(|| format!("Malformed iolog line \"{line}\""))?;
+            Ok(field.parse().map_err(|_| format!("Malformed iolog line \"{line}\""))?)
+        };
+        // [timestamp] filename action [offset length]
+        let first = if timestamped {
+            elapsed = Duration::from_millis(number(0)?);
+            1
+        } else {
+            0
+        };
+        let Some(&action) = fields.get(first + 1) else {
+            return Err(format!("Malformed iolog line \"{line}\"").into());
+        };
+
+        let opcode = match action {
+            "read" => ReplayOpcode::Read,
+            "write" => ReplayOpcode::Write,
+            "sync" | "datasync" => ReplayOpcode::Flush,
+            "wait" => {
+                elapsed += Duration::from_micros(number(first + 2)?);
+                continue;
+            }
+            // add, open and close carry no I/O, trims aren't replayed
+            _ => continue,
+        };
+        let (lba, blocks) = if opcode == ReplayOpcode::Flush {
+            (0, 0)
+        } else {
+            let offset = number(first + 2)?;
+            let len = number(first + 3)?;
+            if len == 0 {
+                continue;
+            }
+            (offset / block_size, len.div_ceil(block_size))
+        };
+        ops.push(ReplayOp {
+            timestamp: elapsed,
+            ns_id,
+            opcode,
+            lba,
+            blocks,
+            recorded_latency: None,
+        });
+    }
+    Ok(ops)
+}
+
+/// Parses the default text output of blkparse. Requests issued to the driver (D) become ops, the recorded
+/// latency is the time until their completion (C). Sectors are converted into blocks of `block_size`.
+pub fn parse_blkparse(text: &str, ns_id: u32, block_size: u64) -> Result<Vec<ReplayOp>, Box<dyn Error>> {
+    if block_size == 0 {
+        return Err("Block size must not be 0".into());
+    }
+    let mut ops: Vec<ReplayOp> = Vec::new();
+    // (sector, sectors) -> indices of issued ops, oldest first
+    let mut pending: HashMap<(u64, u64), VecDeque<usize>> = HashMap::new();
+    let mut start = None;
+    for line in text.lines() {
+        // device, cpu, sequence, time, pid, action, rwbs, sector, "+", sectors, ...
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 10 || fields[8] != "+" {
+            // Summaries and events without a sector range, like plugs
+            continue;
+        }
+        let Ok(time) = fields[3].parse::<f64>() else {
+            continue;
+        };
+        let malformed = || format!("Malformed blkparse line \"{line}\"");
+        let sector: u64 = fields[7].parse().map_err(|_| malformed())?;
+        let sectors: u64 = fields[9].parse().map_err(|_| malformed())?;
+        let rwbs = fields[6];
+
+        match fields[5] {
+            "D" => {
+                let opcode = if rwbs.contains('D') {
+                    continue; // Discard
+                } else if rwbs.contains('R') && sectors > 0 {
+                    ReplayOpcode::Read
+                } else if rwbs.contains('W') && sectors > 0 {
+                    ReplayOpcode::Write
+                } else if rwbs.contains('F') {
+                    ReplayOpcode::Flush
+                } else {
+                    continue;
+                };
+                let (lba, blocks) = if opcode == ReplayOpcode::Flush {
+                    (0, 0)
+                } else {
+                    (sector * SECTOR_SIZE / block_size, (sectors * SECTOR_SIZE).div_ceil(block_size))
+                };
+                let start = *start.get_or_insert(time);
+                pending.entry((sector, sectors)).or_default().push_back(ops.len());
+                ops.push(ReplayOp {
+                    timestamp: Duration::from_secs_f64((time - start).max(0.0)),
+                    ns_id,
+                    opcode,
+                    lba,
+                    blocks,
+                    recorded_latency: None,
+                });
+            }
+            "C" => {
+                let Some(start) = start else {
+                    continue;
+                };
+                if let Some(idx) = pending.get_mut(&(sector, sectors)).and_then(VecDeque::pop_front) {
+                    let completed = Duration::from_secs_f64((time - start).max(0.0));
+                    ops[idx].recorded_latency = Some(completed.saturating_sub(ops[idx].timestamp));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(ops)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayTiming {
+    // Ops are issued at their recorded time, or as soon as the queue depth allows if they fall behind
+    Original,
+    AsFastAsPossible,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayConfig {
+    pub timing: ReplayTiming,
+    pub queue_depth: usize, // Ops outstanding at once, limited by the target
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            timing: ReplayTiming::Original,
+            queue_depth: 32,
+        }
+    }
+}
+
+/// Executes replayed ops, each outstanding op is identified by a tag below the queue depth
+pub trait ReplayTarget {
+    /// Number of ops that can be outstanding at once
+    fn max_queue_depth(&self) -> usize;
+
+    /// Largest op in blocks, larger ops are split before they are submitted
+    fn max_blocks(&self) -> u64;
+
+    /// Issues `op`, returns false if the target can't take it right now
+    fn submit(&mut self, tag: u16, op: &ReplayOp) -> bool;
+
+    /// Tag and success of the next finished op, None if no op finished
+    fn poll(&mut self) -> Option<(u16, bool)>;
+}
+
+/// Replays onto an I/O queue pair. Data is transferred from and into scratch buffers,
+/// so replayed writes overwrite the recorded ranges with junk.
+pub struct QueuePairTarget<'a> {
+    qpair: &'a mut NvmeQueuePair,
+    block_size: u64,
+    tags: usize,
+    buffers: Vec<Dma<u8>>, // MAX_OP_BYTES per tag
+    prp_lists: Dma<u8>,
+}
+
+impl<'a> QueuePairTarget<'a> {
+    pub fn new(qpair: &'a mut NvmeQueuePair, block_size: u64, queue_depth: usize) -> Result<Self, Box<dyn Error>> {
+        if block_size == 0 {
+            return Err("Block size must not be 0".into());
+        }
+        if block_size as usize > MAX_OP_BYTES {
+            return Err(format!("Block size {block_size} exceeds the replay transfer size").into());
+        }
+        let tags = queue_depth.clamp(1, (qpair.sub_queue.len() - 1).min(MAX_TAGS));
+        let buffers = (0..tags.div_ceil(TAGS_PER_BUFFER))
+            .map(|_| Dma::allocate(HUGE_PAGE_SIZE))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            qpair,
+            block_size,
+            tags,
+            buffers,
+            prp_lists: Dma::allocate(HUGE_PAGE_SIZE)?,
+        })
+    }
+
+    // Data pointers of the buffer of `tag`, using its PRP list for more than two pages
+    fn data_pointers(&mut self, tag: usize, bytes: usize) -> (u64, u64) {
+        let addr = (self.buffers[tag / TAGS_PER_BUFFER].phys + tag % TAGS_PER_BUFFER * MAX_OP_BYTES) as u64;
+        let pages = bytes.div_ceil(PAGE_SIZE);
+        let ptr1 = match pages {
+            0 | 1 => 0,
+            2 => addr + PAGE_SIZE as u64,
+            _ => {
+                let list = unsafe { (self.prp_lists.virt as *mut u64).add(tag * PAGE_SIZE / 8) };
+                for i in 1..pages {
+                    unsafe { list.add(i - 1).write(addr + (i * PAGE_SIZE) as u64) };
+                }
+                (self.prp_lists.phys + tag * PAGE_SIZE) as u64
+            }
+        };
+        (addr, ptr1)
+    }
+}
+
+impl ReplayTarget for QueuePairTarget<'_> {
+    fn max_queue_depth(&self) -> usize {
+        self.tags
+    }
+
+    fn max_blocks(&self) -> u64 {
+        MAX_OP_BYTES as u64 / self.block_size
+    }
+
+    fn submit(&mut self, tag: u16, op: &ReplayOp) -> bool {
+        let entry = match op.opcode {
+            ReplayOpcode::Flush => NvmeCommand::io_flush(tag, op.ns_id),
+            ReplayOpcode::Read | ReplayOpcode::Write => {
+                let (ptr0, ptr1) = self.data_pointers(tag as usize, (op.blocks * self.block_size) as usize);
+                let build = if op.opcode == ReplayOpcode::Read {
+                    NvmeCommand::io_read
+                } else {
+                    NvmeCommand::io_write
+                };
+                build(tag, op.ns_id, op.lba, op.blocks as u16 - 1, ptr0, ptr1)
+            }
+        };
+        self.qpair.submit_command(entry).is_some()
+    }
+
+    fn poll(&mut self) -> Option<(u16, bool)> {
+        self.qpair
+            .poll_completion()
+            .map(|c_entry| (c_entry.c_id, c_entry.status >> 1 == 0))
+    }
+}
+
+/// Software backend replaying into a sparse RAM disk, to validate a recording or get a baseline without a device.
+/// Ops complete synchronously on submission and namespace ids are ignored.
+pub struct MemoryTarget {
+    blocks: u64,
+    block_size: u64,
+    queue_depth: usize,
+    data: HashMap<u64, Box<[u8]>>, // Written blocks by lba
+    scratch: Vec<u8>,
+    completed: VecDeque<(u16, bool)>,
+}
+
+impl MemoryTarget {
+    /// A `block_size` of 0 is treated as 1 byte
+    pub fn new(blocks: u64, block_size: u64, queue_depth: usize) -> Self {
+        let block_size = block_size.max(1);
+        Self {
+            blocks,
+            block_size,
+            queue_depth: queue_depth.max(1),
+            data: HashMap::new(),
+            scratch: vec![0; MAX_OP_BYTES.max(block_size as usize)],
+            completed: VecDeque::new(),
+        }
+    }
+}
+
+impl ReplayTarget for MemoryTarget {
+    fn max_queue_depth(&self) -> usize {
+        self.queue_depth
+    }
+
+    fn max_blocks(&self) -> u64 {
+        (MAX_OP_BYTES as u64 / self.block_size).max(1)
+    }
+
+    fn submit(&mut self, tag: u16, op: &ReplayOp) -> bool {
+        let in_range = op.lba.checked_add(op.blocks).is_some_and(|end| end <= self.blocks);
+        if in_range {
+            let block_size = self.block_size as usize;
+            for (i, chunk) in self.scratch.chunks_mut(block_size).take(op.blocks as usize).enumerate() {
+                let lba = op.lba + i as u64;
+                match op.opcode {
+                    ReplayOpcode::Read => match self.data.get(&lba) {
+                        Some(block) => chunk.copy_from_slice(block),
+                        None => chunk.fill(0),
+                    },
+                    ReplayOpcode::Write => {
+                        self.data.insert(lba, chunk.into());
+                    }
+                    ReplayOpcode::Flush => {}
+                }
+            }
+        }
+        self.completed.push_back((tag, in_range));
+        true
+    }
+
+    fn poll(&mut self) -> Option<(u16, bool)> {
+        self.completed.pop_front()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayResult {
+    pub op: ReplayOp,
+    pub latency: Duration,
+    pub success: bool,
+}
+
+impl ReplayResult {
+    /// Replayed minus recorded latency in nanoseconds, None if the latency wasn't recorded
+    pub fn latency_difference(&self) -> Option<i64> {
+        let recorded = self.op.recorded_latency?;
+        Some(self.latency.as_nanos() as i64 - recorded.as_nanos() as i64)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayReport {
+    pub results: Vec<ReplayResult>, // In op order
+    pub elapsed: Duration,
+    pub max_lag: Duration, // Largest delay of an op behind its recorded time, zero unless replayed with original timing
+}
+
+impl ReplayReport {
+    pub fn errors(&self) -> usize {
+        self.results.iter().filter(|result| !result.success).count()
+    }
+
+    /// `p`th percentile of the latency of successful replayed ops
+    pub fn latency_percentile(&self, p: f64) -> Option<Duration> {
+        percentile(self.results.iter().filter(|r| r.success).map(|r| r.latency).collect(), p)
+    }
+
+    /// `p`th percentile of the latency in the recording
+    pub fn recorded_latency_percentile(&self, p: f64) -> Option<Duration> {
+        percentile(self.results.iter().filter_map(|r| r.op.recorded_latency).collect(), p)
+    }
+
+    /// Mean difference of replayed and recorded latency in nanoseconds, over successful ops with a recorded latency
+    pub fn mean_latency_difference(&self) -> Option<f64> {
+        let differences: Vec<i64> = self
+            .results
+            .iter()
+            .filter(|r| r.success)
+            .filter_map(ReplayResult::latency_difference)
+            .collect();
+        if differences.is_empty() {
+            return None;
+        }
+        Some(differences.iter().map(|&d| d as f64).sum::<f64>() / differences.len() as f64)
+    }
+}
+
+fn percentile(mut latencies: Vec<Duration>, p: f64) -> Option<Duration> {
+    if latencies.is_empty() {
+        return None;
+    }
+    latencies.sort_unstable();
+    let idx = ((p / 100.0).clamp(0.0, 1.0) * (latencies.len() - 1) as f64).round() as usize;
+    Some(latencies[idx])
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        writeln!(
+            f,
+            "{} ops ({} failed) in {:.3}s, {:.0} IOPS",
+            self.results.len(),
+            self.errors(),
+            secs,
+            if secs > 0.0 { self.results.len() as f64 / secs } else { 0.0 }
+        )?;
+        if !self.max_lag.is_zero() {
+            writeln!(f, "max lag behind recorded timing: {:?}", self.max_lag)?;
+        }
+        let us = |latency: Option<Duration>| match latency {
+            Some(latency) => format!("{:.1}", latency.as_nanos() as f64 / 1000.0),
+            None => "-".to_string(),
+        };
+        writeln!(f, "{:<10}{:>14}{:>14}", "latency", "replay (us)", "recorded (us)")?;
+        for (name, p) in [("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("p99.9", 99.9), ("max", 100.0)] {
+            writeln!(
+                f,
+                "{:<10}{:>14}{:>14}",
+                name,
+                us(self.latency_percentile(p)),
+                us(self.recorded_latency_percentile(p))
+            )?;
+        }
+        match self.mean_latency_difference() {
+            Some(diff) => write!(f, "mean difference: {:+.1}us", diff / 1000.0),
+            None => write!(f, "mean difference: -"),
+        }
+    }
+}
+
+// Splits ops larger than `max_blocks`, the parts lose the recorded latency of the whole op
+fn split_ops(ops: &[ReplayOp], max_blocks: u64) -> Vec<ReplayOp> {
+    let mut split = Vec::with_capacity(ops.len());
+    for op in ops {
+        if op.blocks <= max_blocks {
+            split.push(*op);
+            continue;
+        }
+        let mut lba = op.lba;
+        let end = op.lba + op.blocks;
+        while lba < end {
+            let blocks = max_blocks.min(end - lba);
+            split.push(ReplayOp {
+                lba,
+                blocks,
+                recorded_latency: None,
+                ..*op
+            });
+            lba += blocks;
+        }
+    }
+    split
+}
+
+/// Issues `ops` in order on `target` and measures the latency of each op.
+/// Ops larger than the target's transfer size are split into several ops without a recorded latency.
+pub fn replay<T: ReplayTarget>(target: &mut T, ops: &[ReplayOp], config: &ReplayConfig) -> ReplayReport {
+    let ops = split_ops(ops, target.max_blocks());
+    let queue_depth = config.queue_depth.clamp(1, target.max_queue_depth());
+    let original_timing = config.timing == ReplayTiming::Original;
+
+    let mut free_tags: Vec<u16> = (0..queue_depth as u16).rev().collect();
+    // Op index and submission time by tag
+    let mut in_flight: Vec<Option<(usize, Instant)>> = vec![None; queue_depth];
+    let mut results: Vec<Option<ReplayResult>> = vec![None; ops.len()];
+    let mut max_lag = Duration::ZERO;
+    let mut next = 0;
+
+    let start = Instant::now();
+    while next < ops.len() || free_tags.len() < queue_depth {
+        while let Some((tag, success)) = target.poll() {
+            if let Some((idx, submitted)) = in_flight.get_mut(tag as usize).and_then(Option::take) {
+                results[idx] = Some(ReplayResult {
+                    op: ops[idx],
+                    latency: submitted.elapsed(),
+                    success,
+                });
+                free_tags.push(tag);
+            }
+        }
+
+        if let (Some(op), Some(&tag)) = (ops.get(next), free_tags.last()) {
+            let now = Instant::now();
+            let offset = now - start;
+            if (!original_timing || offset >= op.timestamp) && target.submit(tag, op) {
+                if original_timing {
+                    max_lag = max_lag.max(offset - op.timestamp);
+                }
+                free_tags.pop();
+                in_flight[tag as usize] = Some((next, now));
+                next += 1;
+                continue;
+            }
+        }
+        spin_loop();
+    }
+
+    ReplayReport {
+        results: results.into_iter().flatten().collect(),
+        elapsed: start.elapsed(),
+        max_lag,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op(timestamp: Duration, opcode: ReplayOpcode, lba: u64, blocks: u64) -> ReplayOp {
+        ReplayOp {
+            timestamp,
+            ns_id: 1,
+            opcode,
+            lba,
+            blocks,
+            recorded_latency: None,
+        }
+    }
+
+    #[test]
+    fn fio_v2_iolog() {
+        let log = "fio version 2 iolog\n\
+                   /dev/nvme0n1 add\n\
+                   /dev/nvme0n1 open\n\
+                   /dev/nvme0n1 read 8192 4096\n\
+                   /dev/nvme0n1 wait 250\n\
+                   /dev/nvme0n1 write 4096 6000\n\
+                   /dev/nvme0n1 wait 250\n\
+                   /dev/nvme0n1 trim 0 4096\n\
+                   /dev/nvme0n1 read 0 0\n\
+                   /dev/nvme0n1 sync 0 0\n\
+                   \n\
+                   /dev/nvme0n1 close\n";
+        let ops = parse_fio_iolog(log, 1, 4096).unwrap();
+        assert_eq!(
+            ops,
+            [
+                op(Duration::ZERO, ReplayOpcode::Read, 2, 1),
+                op(Duration::from_micros(250), ReplayOpcode::Write, 1, 2),
+                op(Duration::from_micros(500), ReplayOpcode::Flush, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn fio_v3_iolog_timestamps_are_milliseconds() {
+        let log = "fio version 3 iolog\n\
+                   0 /dev/nvme0n1 add\n\
+                   3 /dev/nvme0n1 write 1048576 512\n\
+                   10 /dev/nvme0n1 read 512 1024\n";
+        let ops = parse_fio_iolog(log, 1, 512).unwrap();
+        assert_eq!(
+            ops,
+            [
+                op(Duration::from_millis(3), ReplayOpcode::Write, 2048, 1),
+                op(Duration::from_millis(10), ReplayOpcode::Read, 1, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_fio_iologs() {
+        assert!(parse_fio_iolog("", 1, 4096).is_err());
+        assert!(parse_fio_iolog("fio version 4 iolog\n", 1, 4096).is_err());
+        for line in [
+            "/dev/nvme0n1",
+            "/dev/nvme0n1 read 4096",
+            "/dev/nvme0n1 read four 4096",
+            "/dev/nvme0n1 write 4096 -1",
+            "/dev/nvme0n1 wait",
+        ] {
+            let log = format!("fio version 2 iolog\n{line}\n");
+            assert!(parse_fio_iolog(&log, 1, 4096).is_err(), "{line}");
+        }
+        let log = "fio version 3 iolog\nsoon /dev/nvme0n1 read 0 4096\n";
+        assert!(parse_fio_iolog(log, 1, 4096).is_err());
+        assert!(parse_fio_iolog("fio version 2 iolog\n/dev/nvme0n1 read 0 4096\n", 1, 0).is_err());
+    }
+
+    #[test]
+    fn blkparse_issues_and_completions() {
+        let trace = "259,0    3        1     1.000000000  1234  Q   W 16 + 8 [fio]\n\
+                     259,0    3        2     1.500000000  1234  D   W 16 + 8 [fio]\n\
+                     259,0    3        3     1.500000000  1234  D   R 64 + 16 [fio]\n\
+                     259,0    3        4     1.750000000  1234  U   N [fio] 1\n\
+                     259,0    3        5     1.750000000  1234  D   DS 128 + 8 [fio]\n\
+                     259,0    3        6     1.750000000  1234  D FWS 0 + 0 [fio]\n\
+                     259,0    3        7     2.000000000     0  C   W 16 + 8 [0]\n\
+                     259,0    3        8     2.000000000     0  C   W 512 + 8 [0]\n\
+                     CPU3 (fio):\n \
+                     Reads Queued:           0,        0KiB\t Writes Queued:           1,        4KiB\n";
+        let ops = parse_blkparse(trace, 1, 4096).unwrap();
+        assert_eq!(
+            ops,
+            [
+                ReplayOp {
+                    recorded_latency: Some(Duration::from_millis(500)),
+                    ..op(Duration::ZERO, ReplayOpcode::Write, 2, 1)
+                },
+                op(Duration::ZERO, ReplayOpcode::Read, 8, 2),
+                op(Duration::from_millis(250), ReplayOpcode::Flush, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn blkparse_sectors_round_up_to_blocks() {
+        let trace = "259,0 0 1 0.000000000 1 D R 7 + 3 [fio]\n";
+        let ops = parse_blkparse(trace, 1, 1024).unwrap();
+        assert_eq!(ops, [op(Duration::ZERO, ReplayOpcode::Read, 3, 2)]);
+    }
+
+    #[test]
+    fn malformed_blkparse_lines() {
+        assert!(parse_blkparse("259,0 0 1 0.5 1 D W sixteen + 8 [fio]\n", 1, 4096).is_err());
+        assert!(parse_blkparse("259,0 0 1 0.5 1 D W 16 + eight [fio]\n", 1, 4096).is_err());
+        assert!(parse_blkparse("259,0 0 1 0.5 1 D W 16 + 8 [fio]\n", 1, 0).is_err());
+        // Lines without a timestamp aren't trace events
+        assert!(parse_blkparse("259,0 0 1 later 1 D W 16 + 8 [fio]\n", 1, 4096).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,131 @@
+// Reservations for coordinating access to a shared namespace
+// See Section 8.19 of the NVMe Base Specification 2.0
+
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationType {
+    WriteExclusive = 1,
+    ExclusiveAccess = 2,
+    WriteExclusiveRegistrantsOnly = 3,
+    ExclusiveAccessRegistrantsOnly = 4,
+    WriteExclusiveAllRegistrants = 5,
+    ExclusiveAccessAllRegistrants = 6,
+}
+
+impl ReservationType {
+    pub fn from_raw(raw: u8) -> Option<Self> {
+        match raw {
+            1 => Some(ReservationType::WriteExclusive),
+            2 => Some(ReservationType::ExclusiveAccess),
+            3 => Some(ReservationType::WriteExclusiveRegistrantsOnly),
+            4 => Some(ReservationType::ExclusiveAccessRegistrantsOnly),
+            5 => Some(ReservationType::WriteExclusiveAllRegistrants),
+            6 => Some(ReservationType::ExclusiveAccessAllRegistrants),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationRegisterAction {
+    Register = 0,
+    Unregister = 1,
+    Replace = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationAcquireAction {
+    Acquire = 0,
+    /// Removes the registrations of the holder of the preempted key, taking over its reservation
+    Preempt = 1,
+    /// Like `Preempt`, additionally aborting the commands of the preempted hosts
+    PreemptAndAbort = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationReleaseAction {
+    Release = 0,
+    /// Releases the reservation and removes all registrations
+    Clear = 1,
+}
+
+/// A host registered with the namespace, from the Reservation Report data structure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registrant {
+    pub controller_id: u16, // 0xFFFF if the controller is unknown, e.g. behind another subsystem port
+    pub holds_reservation: bool,
+    pub host_id: [u8; 16], // Only the first 8 bytes are used unless extended host identifiers are supported
+    pub key: u64,
+}
+
+/// Parsed Reservation Report of a namespace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReservationStatus {
+    pub generation: u32, // Incremented on every register, preempt and clear
+    pub reservation_type: Option<ReservationType>, // None if the namespace isn't reserved
+    pub persist_through_power_loss: bool,
+    pub registrants: Vec<Registrant>,
+}
+
+impl ReservationStatus {
+    pub fn holder(&self) -> Option<&Registrant> {
+        self.registrants.iter().find(|r| r.holds_reservation)
+    }
+
+    // Parses the (extended) Reservation Status data structure
+    // See Figures 396 to 399 of the NVMe Base Specification 2.0
+    pub(crate) fn parse(data: &[u8], extended: bool) -> Self {
+        let u64_at = |entry: &[u8], i: usize| u64::from_le_bytes(entry[i..i + 8].try_into().unwrap());
+        let (header_len, entry_len) = if extended { (64, 64) } else { (24, 24) };
+        let n_registrants = u16::from_le_bytes([data[5], data[6]]) as usize;
+
+        let registrants = data[header_len..]
+            .chunks_exact(entry_len)
+            .take(n_registrants)
+            .map(|entry| {
+                let mut host_id = [0u8; 16];
+                let key = if extended {
+                    host_id.copy_from_slice(&entry[16..32]);
+                    u64_at(entry, 8)
+                } else {
+                    host_id[..8].copy_from_slice(&entry[8..16]);
+                    u64_at(entry, 16)
+                };
+                Registrant {
+                    controller_id: u16::from_le_bytes([entry[0], entry[1]]),
+                    holds_reservation: entry[2] & 1 == 1,
+                    host_id,
+                    key,
+                }
+            })
+            .collect();
+
+        Self {
+            generation: u32::from_le_bytes(data[..4].try_into().unwrap()),
+            reservation_type: ReservationType::from_raw(data[4]),
+            persist_through_power_loss: data[9] & 1 == 1,
+            registrants,
+        }
+    }
+}
+
+/// Returned when a command fails because another host holds a conflicting reservation,
+/// e.g. after this host got fenced by a peer. Check for it with `err.downcast_ref::<ReservationConflict>()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReservationConflict;
+
+impl fmt::Display for ReservationConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reservation conflict")
+    }
+}
+
+impl Error for ReservationConflict {}
+
+// Generic command status Reservation Conflict
+pub(crate) fn is_reservation_conflict(status: u16) -> bool {
+    let status = status >> 1;
+    (status >> 8) & 0x7 == 0 && status & 0xFF == 0x83
+}
@@ -0,0 +1,389 @@
+// Thread-per-core runtime: one pinned worker per core, each owning a queue pair and a DMA pool
+// on the device's NUMA node, fed with requests from other threads over channels
+
+use crate::memory::DmaPool;
+use crate::nvme::{NvmeDevice, NvmeQueuePair};
+use crate::poll_group::{PollGroup, QueueHandle};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::mem;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+// Memory policy modes, see set_mempolicy(2)
+const MPOL_DEFAULT: i32 = 0;
+const MPOL_PREFERRED: i32 = 1;
+// Nodes covered by the node mask passed to set_mempolicy
+const MAX_NUMA_NODES: usize = 64;
+
+/// Work routed to a core, it runs on the worker thread with access to the core's queue pair and buffers
+pub type Job = Box<dyn FnOnce(&mut WorkerContext) + Send>;
+
+enum Message {
+    Job(Job),
+    Stop,
+}
+
+/// State owned by a worker, handed to every job running on its core
+pub struct WorkerContext {
+    pub core: usize,
+    pub numa_node: Option<usize>,
+    pub group: PollGroup, // Holds the core's queue pair, submit through it with `queue`
+    pub queue: QueueHandle,
+    pub pool: DmaPool,
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeConfig {
+    pub cores: Vec<usize>, // One worker per core, empty selects the cores of the device's NUMA node
+    pub queue_len: usize,
+    pub buffer_size: usize, // Of the DMA pool buffers of every worker
+    pub buffers_per_core: usize,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            cores: Vec::new(),
+            queue_len: 1024,
+            buffer_size: 128 * 1024,
+            buffers_per_core: 64,
+        }
+    }
+}
+
+struct Worker {
+    core: usize,
+    sender: Sender<Message>,
+    thread: JoinHandle<NvmeQueuePair>,
+}
+
+/// Pinned workers sharing one device, see `RuntimeConfig`.
+/// Dropping it without `shutdown` still stops the workers and deletes their queue pairs.
+pub struct Runtime<'a> {
+    nvme: &'a mut NvmeDevice, // Deletes the queue pairs of the workers once they stopped
+    workers: Vec<Worker>,
+    numa_node: Option<usize>,
+}
+
+/// NUMA node of the PCI device at `pci_addr`, None if the system doesn't report one
+pub fn device_numa_node(pci_addr: &str) -> Result<Option<usize>, Box<dyn Error>> {
+    let node = match fs::read_to_string(format!("/sys/bus/pci/devices/{pci_addr}/numa_node")) {
+        Ok(node) => node,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    // -1 on systems without NUMA
+    Ok(node.trim().parse::<i64>()?.try_into().ok())
+}
+
+/// Cores of NUMA node `node`, parsed from its sysfs cpulist like "0-3,8-11"
+pub fn numa_node_cores(node: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+    let list = fs::read_to_string(format!("/sys/devices/system/node/node{node}/cpulist"))?;
+    let mut cores = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((first, last)) => cores.extend(first.parse::<usize>()?..=last.parse::<usize>()?),
+            None => cores.push(range.parse()?),
+        }
+    }
+    Ok(cores)
+}
+
+/// Pins the calling thread to `core`
+pub fn pin_to_core(core: usize) -> Result<(), Box<dyn Error>> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(format!("Pinning to core {core} failed: {}", io::Error::last_os_error()).into());
+        }
+    }
+    Ok(())
+}
+
+// Memory policy of a thread, see set_mempolicy(2)
+struct MemPolicy {
+    mode: i32,
+    nodes: u64, // Node mask covering MAX_NUMA_NODES nodes
+}
+
+impl MemPolicy {
+    // Policy of the calling thread, to restore it later
+    fn current() -> Result<Self, Box<dyn Error>> {
+        let mut policy = Self {
+            mode: MPOL_DEFAULT,
+            nodes: 0,
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_get_mempolicy,
+                &mut policy.mode as *mut i32,
+                &mut policy.nodes as *mut u64,
+                MAX_NUMA_NODES + 1,
+                std::ptr::null::<u8>(),
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(format!("Getting the NUMA memory policy failed: {}", io::Error::last_os_error()).into());
+        }
+        Ok(policy)
+    }
+
+    // Makes memory first touched by a thread prefer `node`
+    fn preferred(node: usize) -> Result<Self, Box<dyn Error>> {
+        if node >= MAX_NUMA_NODES {
+            return Err(format!("NUMA node {node} is out of range").into());
+        }
+        Ok(Self {
+            mode: MPOL_PREFERRED,
+            nodes: 1 << node,
+        })
+    }
+
+    // Sets the policy of the calling thread
+    fn apply(&self) -> Result<(), Box<dyn Error>> {
+        // The kernel expects one more than the number of mask bits
+        let ret = unsafe { libc::syscall(libc::SYS_set_mempolicy, self.mode, &self.nodes as *const u64, MAX_NUMA_NODES + 1) };
+        if ret != 0 {
+            return Err(format!("Setting the NUMA memory policy failed: {}", io::Error::last_os_error()).into());
+        }
+        Ok(())
+    }
+}
+
+// Creates `n` queue pairs, deleting the created ones again if one fails
+fn create_queue_pairs(nvme: &mut NvmeDevice, n: usize, len: usize) -> Result<Vec<NvmeQueuePair>, Box<dyn Error>> {
+    let mut qpairs = Vec::with_capacity(n);
+    for _ in 0..n {
+        match nvme.create_io_queue_pair(len) {
+            Ok(qpair) => qpairs.push(qpair),
+            Err(e) => {
+                if let Err(cleanup) = delete_queue_pairs(nvme, qpairs) {
+                    eprintln!("Deleting the created queue pairs failed: {cleanup}");
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(qpairs)
+}
+
+// Deletes all queue pairs even if some fail, returns the first error
+fn delete_queue_pairs(nvme: &mut NvmeDevice, qpairs: impl IntoIterator<Item = NvmeQueuePair>) -> Result<(), Box<dyn Error>> {
+    let mut result = Ok(());
+    for qpair in qpairs {
+        result = result.and(nvme.delete_io_queue_pair(qpair));
+    }
+    result
+}
+
+impl<'a> Runtime<'a> {
+    /// Creates a queue pair per core on `nvme` and starts the workers.
+    /// Queue and buffer memory is placed on the device's NUMA node where the system reports one.
+    pub fn spawn(nvme: &'a mut NvmeDevice, config: RuntimeConfig) -> Result<Self, Box<dyn Error>> {
+        let numa_node = device_numa_node(nvme.pci_addr())?;
+        let cores = match (&config.cores[..], numa_node) {
+            ([], Some(node)) => numa_node_cores(node)?,
+            ([], None) => return Err("No cores selected and the device reports no NUMA node".into()),
+            (cores, _) => cores.to_vec(),
+        };
+
+        // Queues are faulted in by this thread while creating them, its own policy is restored afterwards
+        let qpairs = match numa_node {
+            Some(node) => {
+                let previous = MemPolicy::current()?;
+                let qpairs = MemPolicy::preferred(node)
+                    .and_then(|policy| policy.apply())
+                    .and_then(|_| create_queue_pairs(nvme, cores.len(), config.queue_len));
+                let restored = previous.apply();
+                match (qpairs, restored) {
+                    (Ok(qpairs), Err(e)) => {
+                        if let Err(cleanup) = delete_queue_pairs(nvme, qpairs) {
+                            eprintln!("Deleting the created queue pairs failed: {cleanup}");
+                        }
+                        return Err(e);
+                    }
+                    (qpairs, _) => qpairs?,
+                }
+            }
+            None => create_queue_pairs(nvme, cores.len(), config.queue_len)?,
+        };
+
+        let mut runtime = Self {
+            nvme,
+            workers: Vec::with_capacity(cores.len()),
+            numa_node,
+        };
+        let mut pending = cores.into_iter().zip(qpairs);
+        while let Some((core, qpair)) = pending.next() {
+            if let Err(e) = runtime.start_worker(core, qpair, &config) {
+                // Everything is torn down before reporting why the worker didn't start
+                let cleanup = delete_queue_pairs(runtime.nvme, pending.map(|(_, qpair)| qpair)).and(runtime.stop());
+                if let Err(cleanup) = cleanup {
+                    eprintln!("Stopping the started workers failed: {cleanup}");
+                }
+                return Err(e);
+            }
+        }
+        Ok(runtime)
+    }
+
+    // Starts the worker of `core`, its queue pair is deleted again if the worker doesn't come up
+    fn start_worker(&mut self, core: usize, qpair: NvmeQueuePair, config: &RuntimeConfig) -> Result<(), Box<dyn Error>> {
+        let (sender, receiver) = mpsc::channel();
+        let (ready_sender, ready) = mpsc::channel();
+        // The queue pair is handed over after spawning, so it isn't lost if spawning fails
+        let (qpair_sender, qpair_receiver) = mpsc::channel::<NvmeQueuePair>();
+        let numa_node = self.numa_node;
+        let config = config.clone();
+        let spawned = thread::Builder::new()
+            .name(format!("vroom-core-{core}"))
+            .spawn(move || {
+                let qpair = qpair_receiver.recv().expect("queue pair is sent after spawning");
+                let ctx = pin_to_core(core)
+                    .and_then(|_| match numa_node {
+                        Some(node) => MemPolicy::preferred(node)?.apply(),
+                        None => Ok(()),
+                    })
+                    .and_then(|_| DmaPool::new(config.buffer_size, config.buffers_per_core));
+                match ctx {
+                    Ok(pool) => {
+                        let mut group = PollGroup::new();
+                        let queue = group.add(qpair);
+                        let _ = ready_sender.send(Ok(()));
+                        let ctx = WorkerContext {
+                            core,
+                            numa_node,
+                            group,
+                            queue,
+                            pool,
+                        };
+                        run_worker(ctx, receiver)
+                    }
+                    Err(e) => {
+                        let _ = ready_sender.send(Err(e.to_string()));
+                        qpair
+                    }
+                }
+            });
+        let thread = match spawned {
+            Ok(thread) => thread,
+            Err(e) => {
+                self.delete_unused(qpair);
+                return Err(format!("Starting worker on core {core} failed: {e}").into());
+            }
+        };
+        qpair_sender.send(qpair).expect("worker waits for its queue pair");
+
+        // Workers that failed to start return their queue pair
+        match ready.recv() {
+            Ok(Ok(())) => {
+                self.workers.push(Worker { core, sender, thread });
+                Ok(())
+            }
+            result => {
+                if let Ok(qpair) = thread.join() {
+                    self.delete_unused(qpair);
+                }
+                let e = match result {
+                    Ok(Err(e)) => e,
+                    _ => "worker exited".to_string(),
+                };
+                Err(format!("Starting worker on core {core} failed: {e}").into())
+            }
+        }
+    }
+
+    pub fn cores(&self) -> impl Iterator<Item = usize> + '_ {
+        self.workers.iter().map(|worker| worker.core)
+    }
+
+    pub fn numa_node(&self) -> Option<usize> {
+        self.numa_node
+    }
+
+    /// Runs `job` on the worker pinned to `core`. Jobs of a core run in order, results are sent back by the job itself.
+    pub fn submit<F: FnOnce(&mut WorkerContext) + Send + 'static>(&self, core: usize, job: F) -> Result<(), Box<dyn Error>> {
+        let worker = self
+            .workers
+            .iter()
+            .find(|worker| worker.core == core)
+            .ok_or_else(|| format!("No worker on core {core}"))?;
+        worker
+            .sender
+            .send(Message::Job(Box::new(job)))
+            .map_err(|_| format!("Worker on core {core} exited").into())
+    }
+
+    // Deletes the queue pair of a worker that didn't start, the reason it didn't start is the error to report
+    fn delete_unused(&mut self, qpair: NvmeQueuePair) {
+        let id = qpair.id;
+        if let Err(e) = self.nvme.delete_io_queue_pair(qpair) {
+            eprintln!("Deleting queue pair {id} failed: {e}");
+        }
+    }
+
+    /// The device, e.g. for admin commands while the workers run
+    pub fn device(&mut self) -> &mut NvmeDevice {
+        self.nvme
+    }
+
+    /// Stops the workers once their queued jobs and outstanding I/O finished, and deletes their queue pairs
+    pub fn shutdown(mut self) -> Result<(), Box<dyn Error>> {
+        self.stop()
+    }
+
+    // Stops all workers and deletes their queue pairs even if some fail, returns the first error
+    fn stop(&mut self) -> Result<(), Box<dyn Error>> {
+        let workers = mem::take(&mut self.workers);
+        for worker in &workers {
+            let _ = worker.sender.send(Message::Stop);
+        }
+        let mut result = Ok(());
+        for worker in workers {
+            let deleted = match worker.thread.join() {
+                Ok(qpair) => self.nvme.delete_io_queue_pair(qpair),
+                Err(_) => Err(format!("Worker on core {} panicked", worker.core).into()),
+            };
+            result = result.and(deleted);
+        }
+        result
+    }
+}
+
+impl Drop for Runtime<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            eprintln!("Stopping the runtime failed: {e}");
+        }
+    }
+}
+
+// Runs jobs and polls the queue pair until stopped, returns the queue pair for deletion
+fn run_worker(mut ctx: WorkerContext, receiver: Receiver<Message>) -> NvmeQueuePair {
+    let mut stopping = false;
+    while !stopping {
+        // Without outstanding I/O there is nothing to poll, so the worker sleeps until the next request
+        let message = if ctx.group.outstanding() == 0 {
+            receiver.recv().ok()
+        } else {
+            match receiver.try_recv() {
+                Ok(message) => Some(message),
+                Err(TryRecvError::Empty) => {
+                    ctx.group.poll();
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => None,
+            }
+        };
+        match message {
+            Some(Message::Job(job)) => job(&mut ctx),
+            Some(Message::Stop) | None => stopping = true,
+        }
+    }
+    ctx.group.poll_until_idle();
+    ctx.group.remove(ctx.queue).expect("queue pair is idle")
+}
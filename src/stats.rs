@@ -0,0 +1,416 @@
+// I/O statistics of the submission/completion queue pairs: counters, queue depth samples and
+// latency histograms, collected on the hot path and read through snapshots
+
+use crate::cmd::NvmeCommand;
+use crate::queues::{c_id_slot, NvmeCompletion};
+use crate::NvmeStats;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Latencies below 2^SUB_BUCKET_BITS ns are exact, above every power of two is split into
+// 2^SUB_BUCKET_BITS linear buckets, bounding the relative error to 1/32
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+// Largest tracked latency is 2^MAX_EXPONENT ns (about 18 minutes), slower commands land in the last bucket
+const MAX_EXPONENT: u32 = 40;
+const BUCKETS: usize = (MAX_EXPONENT - SUB_BUCKET_BITS + 2) as usize * SUB_BUCKETS;
+
+const OPCODE_WRITE: u8 = 0x01;
+const OPCODE_READ: u8 = 0x02;
+const OPCODE_ZONE_APPEND: u8 = 0x7D;
+
+/// HDR-style histogram of latencies with nanosecond resolution and ~3% precision
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; BUCKETS],
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+}
+
+fn bucket_index(ns: u64) -> usize {
+    if ns < SUB_BUCKETS as u64 {
+        return ns as usize;
+    }
+    let exponent = 63 - ns.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let sub_bucket = (ns >> shift) as usize - SUB_BUCKETS;
+    (SUB_BUCKETS + shift as usize * SUB_BUCKETS + sub_bucket).min(BUCKETS - 1)
+}
+
+// Highest latency in nanoseconds that falls into bucket `idx`
+fn bucket_upper_bound(idx: usize) -> u64 {
+    if idx < SUB_BUCKETS {
+        return idx as u64;
+    }
+    let shift = (idx - SUB_BUCKETS) / SUB_BUCKETS;
+    let sub_bucket = (idx - SUB_BUCKETS) % SUB_BUCKETS;
+    (((SUB_BUCKETS + sub_bucket) as u64) << shift) + (1 << shift) - 1
+}
+
+impl LatencyHistogram {
+    #[inline(always)]
+    pub fn record(&mut self, latency: Duration) {
+        let ns = latency.as_nanos().min(u64::MAX as u128) as u64;
+        self.counts[bucket_index(ns)] += 1;
+        self.count += 1;
+        self.sum += ns as u128;
+        self.min = self.min.min(ns);
+        self.max = self.max.max(ns);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Sum of all recorded latencies
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(self.sum.min(u64::MAX as u128) as u64)
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos(self.min))
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos(self.max))
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos((self.sum / self.count as u128) as u64))
+    }
+
+    /// Latency below which `p` percent of the recorded latencies fall, e.g. `percentile(99.9)`
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((p / 100.0).clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (idx, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                if idx == BUCKETS - 1 {
+                    // Overflow bucket without an upper bound
+                    return self.max();
+                }
+                let ns = bucket_upper_bound(idx).clamp(self.min, self.max);
+                return Some(Duration::from_nanos(ns));
+            }
+        }
+        self.max()
+    }
+
+    /// Non-empty buckets as their upper bound and count, in ascending order
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(idx, &count)| (Duration::from_nanos(bucket_upper_bound(idx)), count))
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Counters of the I/O commands of a queue or namespace
+#[derive(Debug, Clone, Default)]
+pub struct IoStats {
+    pub submissions: u64,
+    pub completions: u64,
+    pub bytes_read: u64, // Of successful commands
+    pub bytes_written: u64,
+    pub ops: Vec<u64>, // Submissions indexed by opcode, only as long as the highest opcode seen
+    pub errors: BTreeMap<u16, u64>, // Failed completions by status code type << 8 | status code
+    pub queue_depth: Vec<u64>, // Outstanding commands sampled on every submission, indexed by depth
+    pub latency: LatencyHistogram, // Submission to completion, of all commands
+    pub read_latency: LatencyHistogram,
+    pub write_latency: LatencyHistogram, // Includes zone appends
+}
+
+impl IoStats {
+    /// Submitted commands with `opcode`
+    pub fn ops(&self, opcode: u8) -> u64 {
+        self.ops.get(opcode as usize).copied().unwrap_or(0)
+    }
+
+    pub fn error_count(&self) -> u64 {
+        self.errors.values().sum()
+    }
+
+    pub fn mean_queue_depth(&self) -> f64 {
+        let samples: u64 = self.queue_depth.iter().sum();
+        if samples == 0 {
+            return 0.0;
+        }
+        let total: u64 = self.queue_depth.iter().enumerate().map(|(depth, &n)| depth as u64 * n).sum();
+        total as f64 / samples as f64
+    }
+
+    pub fn max_queue_depth(&self) -> usize {
+        self.queue_depth.iter().rposition(|&n| n > 0).unwrap_or(0)
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.submissions += other.submissions;
+        self.completions += other.completions;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        merge_counts(&mut self.ops, &other.ops);
+        for (&status, &n) in &other.errors {
+            *self.errors.entry(status).or_default() += n;
+        }
+        merge_counts(&mut self.queue_depth, &other.queue_depth);
+        self.latency.merge(&other.latency);
+        self.read_latency.merge(&other.read_latency);
+        self.write_latency.merge(&other.write_latency);
+    }
+
+    #[inline(always)]
+    fn submitted(&mut self, opcode: u8, depth: usize) {
+        self.submissions += 1;
+        increment(&mut self.ops, opcode as usize);
+        increment(&mut self.queue_depth, depth);
+    }
+
+    #[inline(always)]
+    fn completed(&mut self, status: u16, cmd: Option<(&InFlight, u64, Duration)>) {
+        self.completions += 1;
+        if status != 0 {
+            *self.errors.entry(status).or_default() += 1;
+        }
+        let Some((cmd, block_size, latency)) = cmd else {
+            return;
+        };
+        self.latency.record(latency);
+        let bytes = if status == 0 { cmd.blocks * block_size } else { 0 };
+        match cmd.opcode {
+            OPCODE_READ => {
+                self.read_latency.record(latency);
+                self.bytes_read += bytes;
+            }
+            OPCODE_WRITE | OPCODE_ZONE_APPEND => {
+                self.write_latency.record(latency);
+                self.bytes_written += bytes;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn increment(counts: &mut Vec<u64>, idx: usize) {
+    if counts.len() <= idx {
+        counts.resize(idx + 1, 0);
+    }
+    counts[idx] += 1;
+}
+
+fn merge_counts(counts: &mut Vec<u64>, other: &[u64]) {
+    if counts.len() < other.len() {
+        counts.resize(other.len(), 0);
+    }
+    for (count, other) in counts.iter_mut().zip(other) {
+        *count += other;
+    }
+}
+
+// A submitted command waiting for its completion
+#[derive(Debug, Clone, Copy)]
+struct InFlight {
+    c_id: u16,
+    ns_id: u32,
+    opcode: u8,
+    blocks: u64,
+    submitted: Instant,
+}
+
+/// Statistics shared between the submission and completion queue of a queue pair
+pub type SharedQueueStats = Arc<Mutex<QueueStats>>;
+
+/// Statistics of one I/O queue pair, in total and per namespace
+pub struct QueueStats {
+    q_id: u16,
+    total: IoStats,
+    namespaces: HashMap<u32, IoStats>,
+    block_sizes: HashMap<u32, u64>,
+    // Indexed by the submission queue slot the command id was derived from
+    in_flight: Vec<Option<InFlight>>,
+    outstanding: usize,
+}
+
+impl QueueStats {
+    pub(crate) fn new(q_id: u16, len: usize, block_sizes: HashMap<u32, u64>) -> Self {
+        Self {
+            q_id,
+            total: IoStats::default(),
+            namespaces: HashMap::new(),
+            block_sizes,
+            in_flight: vec![None; len],
+            outstanding: 0,
+        }
+    }
+
+    pub fn q_id(&self) -> u16 {
+        self.q_id
+    }
+
+    pub fn total(&self) -> &IoStats {
+        &self.total
+    }
+
+    pub fn namespace(&self, ns_id: u32) -> Option<&IoStats> {
+        self.namespaces.get(&ns_id)
+    }
+
+    pub fn namespaces(&self) -> impl Iterator<Item = (u32, &IoStats)> {
+        self.namespaces.iter().map(|(&ns_id, stats)| (ns_id, stats))
+    }
+
+    /// Clears all counters, commands outstanding during the reset are still measured when they complete
+    pub fn reset(&mut self) {
+        self.total = IoStats::default();
+        self.namespaces.clear();
+    }
+
+    pub(crate) fn set_block_size(&mut self, ns_id: u32, block_size: u64) {
+        self.block_sizes.insert(ns_id, block_size);
+    }
+
+    #[inline(always)]
+    pub(crate) fn submitted(&mut self, cmd: &NvmeCommand) {
+        let opcode = cmd.opcode;
+        let ns_id = cmd.ns_id;
+        let blocks = match opcode {
+            OPCODE_READ | OPCODE_WRITE | OPCODE_ZONE_APPEND => (cmd.cdw12 & 0xFFFF) as u64 + 1,
+            _ => 0,
+        };
+        self.outstanding += 1;
+        // Command ids not derived from a slot of this queue aren't timed
+        let slot = c_id_slot(cmd.c_id, self.in_flight.len());
+        if let Some(slot) = self.in_flight.get_mut(slot) {
+            *slot = Some(InFlight {
+                c_id: cmd.c_id,
+                ns_id,
+                opcode,
+                blocks,
+                submitted: Instant::now(),
+            });
+        }
+        self.total.submitted(opcode, self.outstanding);
+        if ns_id != 0 {
+            self.namespaces.entry(ns_id).or_default().submitted(opcode, self.outstanding);
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn completed(&mut self, entry: &NvmeCompletion) {
+        let c_id = entry.c_id;
+        let status = (entry.status >> 1) & 0x7FF;
+        self.outstanding = self.outstanding.saturating_sub(1);
+
+        let slot = c_id_slot(c_id, self.in_flight.len());
+        let cmd = match self.in_flight.get_mut(slot) {
+            Some(slot) if slot.as_ref().is_some_and(|cmd| cmd.c_id == c_id) => slot.take(),
+            _ => None,
+        };
+        let Some(cmd) = cmd else {
+            self.total.completed(status, None);
+            return;
+        };
+        let latency = cmd.submitted.elapsed();
+        let block_size = self.block_sizes.get(&cmd.ns_id).copied().unwrap_or(0);
+        self.total.completed(status, Some((&cmd, block_size, latency)));
+        if cmd.ns_id != 0 {
+            self.namespaces
+                .entry(cmd.ns_id)
+                .or_default()
+                .completed(status, Some((&cmd, block_size, latency)));
+        }
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    queues: BTreeMap<u16, SharedQueueStats>,
+    block_sizes: HashMap<u32, u64>,
+}
+
+/// Statistics of all I/O queues of a device. Clones share the same queues, so snapshots can be taken
+/// from another thread while the device and its queue pairs are in use.
+#[derive(Clone, Default)]
+pub struct StatsRegistry {
+    inner: Arc<Mutex<Registry>>,
+}
+
+impl StatsRegistry {
+    pub(crate) fn register(&self, q_id: u16, len: usize) -> SharedQueueStats {
+        let mut inner = self.inner.lock().unwrap();
+        let stats = Arc::new(Mutex::new(QueueStats::new(q_id, len, inner.block_sizes.clone())));
+        inner.queues.insert(q_id, stats.clone());
+        stats
+    }
+
+    pub(crate) fn unregister(&self, q_id: u16) {
+        self.inner.lock().unwrap().queues.remove(&q_id);
+    }
+
+    pub(crate) fn set_block_size(&self, ns_id: u32, block_size: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.block_sizes.insert(ns_id, block_size);
+        for stats in inner.queues.values() {
+            stats.lock().unwrap().set_block_size(ns_id, block_size);
+        }
+    }
+
+    /// Copies the statistics of every queue and merges them per namespace
+    pub fn snapshot(&self) -> NvmeStats {
+        let queues: Vec<SharedQueueStats> = self.inner.lock().unwrap().queues.values().cloned().collect();
+        let mut snapshot = NvmeStats::default();
+        for stats in queues {
+            let stats = stats.lock().unwrap();
+            snapshot.submissions += stats.total.submissions;
+            snapshot.completions += stats.total.completions;
+            for (ns_id, ns_stats) in stats.namespaces() {
+                snapshot.namespaces.entry(ns_id).or_default().merge(ns_stats);
+            }
+            snapshot.queues.insert(stats.q_id, stats.total.clone());
+        }
+        snapshot
+    }
+
+    pub fn reset(&self) {
+        for stats in self.inner.lock().unwrap().queues.values() {
+            stats.lock().unwrap().reset();
+        }
+    }
+}
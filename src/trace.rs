@@ -0,0 +1,337 @@
+// Command tracing: a ring of the submission and completion entries of a queue pair,
+// with a text decoder and a binary dump format for offline analysis
+
+use crate::cmd::NvmeCommand;
+use crate::queues::NvmeCompletion;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const TRACE_MAGIC: &[u8; 8] = b"VROOMTRC";
+const TRACE_VERSION: u16 = 1;
+
+/// Trace ring shared between the submission and completion queue of a queue pair
+pub type SharedTrace = Arc<Mutex<TraceRing>>;
+
+#[derive(Debug, Clone, Copy)]
+pub enum TraceEntry {
+    Submission(NvmeCommand),
+    Completion(NvmeCompletion),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TraceRecord {
+    pub timestamp: u64, // Nanoseconds since the epoch
+    pub q_id: u16,      // 0 for the admin queue
+    pub entry: TraceEntry,
+}
+
+/// Ring of the most recent trace records of a queue pair, the oldest records are dropped when it's full
+pub struct TraceRing {
+    records: VecDeque<TraceRecord>,
+    capacity: usize,
+    q_id: u16,
+    start: Instant,
+    start_epoch: u64,
+}
+
+impl TraceRing {
+    pub fn new(q_id: u16, capacity: usize) -> Self {
+        let start_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        Self {
+            records: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            q_id,
+            start: Instant::now(),
+            start_epoch,
+        }
+    }
+
+    pub(crate) fn shared(q_id: u16, capacity: usize) -> SharedTrace {
+        Arc::new(Mutex::new(Self::new(q_id, capacity)))
+    }
+
+    #[inline(always)]
+    pub(crate) fn record(&mut self, entry: TraceEntry) {
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        // Monotonic clock, so records stay ordered even if the system time changes
+        let timestamp = self.start_epoch + self.start.elapsed().as_nanos() as u64;
+        self.records.push_back(TraceRecord {
+            timestamp,
+            q_id: self.q_id,
+            entry,
+        });
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &TraceRecord> {
+        self.records.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    /// Writes the records in the binary dump format read by `TraceReader`
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(TRACE_MAGIC)?;
+        writer.write_all(&TRACE_VERSION.to_le_bytes())?;
+        for record in &self.records {
+            record.write_to(&mut writer)?;
+        }
+        writer.flush()
+    }
+}
+
+impl TraceRecord {
+    // Record layout: timestamp (u64), queue id (u16), kind (u8, 0 = submission, 1 = completion),
+    // one reserved byte, followed by the raw 64 byte command or 16 byte completion
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.timestamp.to_le_bytes())?;
+        writer.write_all(&self.q_id.to_le_bytes())?;
+        match &self.entry {
+            TraceEntry::Submission(cmd) => {
+                writer.write_all(&[0, 0])?;
+                writer.write_all(as_bytes(cmd))
+            }
+            TraceEntry::Completion(entry) => {
+                writer.write_all(&[1, 0])?;
+                writer.write_all(as_bytes(entry))
+            }
+        }
+    }
+}
+
+// Raw bytes of a packed queue entry, entries are little endian like the host
+fn as_bytes<T: Copy>(entry: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(entry as *const T as *const u8, size_of::<T>()) }
+}
+
+fn from_bytes<T: Copy>(bytes: &[u8]) -> T {
+    assert_eq!(bytes.len(), size_of::<T>());
+    unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) }
+}
+
+/// Reads trace records from a dump written by `TraceRing::write_to`
+pub struct TraceReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, Box<dyn Error>> {
+        let mut header = [0u8; 10];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != TRACE_MAGIC {
+            return Err("Not a vroom trace dump".into());
+        }
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version != TRACE_VERSION {
+            return Err(format!("Unsupported trace dump version {version}").into());
+        }
+        Ok(Self { reader })
+    }
+
+    fn read_record(&mut self) -> Result<Option<TraceRecord>, Box<dyn Error>> {
+        let mut header = [0u8; 12];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let timestamp = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let q_id = u16::from_le_bytes([header[8], header[9]]);
+        let entry = match header[10] {
+            0 => {
+                let mut data = [0u8; size_of::<NvmeCommand>()];
+                self.reader.read_exact(&mut data)?;
+                TraceEntry::Submission(from_bytes(&data))
+            }
+            1 => {
+                let mut data = [0u8; size_of::<NvmeCompletion>()];
+                self.reader.read_exact(&mut data)?;
+                TraceEntry::Completion(from_bytes(&data))
+            }
+            kind => return Err(format!("Invalid trace record kind {kind}").into()),
+        };
+        Ok(Some(TraceRecord { timestamp, q_id, entry }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceRecord, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+fn admin_opcode_name(opcode: u8) -> Option<&'static str> {
+    Some(match opcode {
+        0x00 => "DELETE_IO_SQ",
+        0x01 => "CREATE_IO_SQ",
+        0x02 => "GET_LOG_PAGE",
+        0x04 => "DELETE_IO_CQ",
+        0x05 => "CREATE_IO_CQ",
+        0x06 => "IDENTIFY",
+        0x08 => "ABORT",
+        0x09 => "SET_FEATURES",
+        0x0A => "GET_FEATURES",
+        0x0C => "ASYNC_EVENT_REQUEST",
+        0x0D => "NAMESPACE_MANAGEMENT",
+        0x10 => "FIRMWARE_COMMIT",
+        0x11 => "FIRMWARE_IMAGE_DOWNLOAD",
+        0x15 => "NAMESPACE_ATTACHMENT",
+        0x19 => "DIRECTIVE_SEND",
+        0x1A => "DIRECTIVE_RECEIVE",
+        0x80 => "FORMAT_NVM",
+        0x84 => "SANITIZE",
+        _ => return None,
+    })
+}
+
+pub(crate) fn io_opcode_name(opcode: u8) -> Option<&'static str> {
+    Some(match opcode {
+        0x00 => "FLUSH",
+        0x01 => "WRITE",
+        0x02 => "READ",
+        0x04 => "WRITE_UNCORRECTABLE",
+        0x05 => "COMPARE",
+        0x08 => "WRITE_ZEROES",
+        0x09 => "DATASET_MANAGEMENT",
+        0x0D => "RESERVATION_REGISTER",
+        0x0E => "RESERVATION_REPORT",
+        0x11 => "RESERVATION_ACQUIRE",
+        0x12 => "IO_MANAGEMENT_RECEIVE",
+        0x15 => "RESERVATION_RELEASE",
+        0x19 => "COPY",
+        0x1D => "IO_MANAGEMENT_SEND",
+        0x79 => "ZONE_MANAGEMENT_SEND",
+        0x7A => "ZONE_MANAGEMENT_RECEIVE",
+        0x7D => "ZONE_APPEND",
+        _ => return None,
+    })
+}
+
+/// Name of a completion status code, see Section 4.6.1.2 of the NVMe Base Specification 2.0
+pub fn status_name(sct: u8, sc: u8) -> &'static str {
+    match (sct, sc) {
+        (0, 0x00) => "SUCCESS",
+        (0, 0x01) => "INVALID_OPCODE",
+        (0, 0x02) => "INVALID_FIELD",
+        (0, 0x03) => "COMMAND_ID_CONFLICT",
+        (0, 0x04) => "DATA_TRANSFER_ERROR",
+        (0, 0x05) => "ABORTED_POWER_LOSS",
+        (0, 0x06) => "INTERNAL_ERROR",
+        (0, 0x07) => "ABORT_REQUESTED",
+        (0, 0x08) => "ABORTED_SQ_DELETION",
+        (0, 0x09) => "ABORTED_FUSED_FAILURE",
+        (0, 0x0A) => "ABORTED_FUSED_MISSING",
+        (0, 0x0B) => "INVALID_NAMESPACE_OR_FORMAT",
+        (0, 0x0C) => "COMMAND_SEQUENCE_ERROR",
+        (0, 0x80) => "LBA_OUT_OF_RANGE",
+        (0, 0x81) => "CAPACITY_EXCEEDED",
+        (0, 0x82) => "NAMESPACE_NOT_READY",
+        (0, 0x83) => "RESERVATION_CONFLICT",
+        (0, 0x84) => "FORMAT_IN_PROGRESS",
+        (1, 0x00) => "COMPLETION_QUEUE_INVALID",
+        (1, 0x01) => "INVALID_QUEUE_IDENTIFIER",
+        (1, 0x02) => "INVALID_QUEUE_SIZE",
+        (1, 0x05) => "ASYNC_EVENT_REQUEST_LIMIT_EXCEEDED",
+        (1, 0x06) => "INVALID_FIRMWARE_SLOT",
+        (1, 0x07) => "INVALID_FIRMWARE_IMAGE",
+        (1, 0x09) => "INVALID_LOG_PAGE",
+        (1, 0x0A) => "INVALID_FORMAT",
+        (1, 0x0D) => "FEATURE_NOT_SAVEABLE",
+        (1, 0x0E) => "FEATURE_NOT_CHANGEABLE",
+        (1, 0x15) => "NAMESPACE_INSUFFICIENT_CAPACITY",
+        (1, 0x1D) => "SANITIZE_PROHIBITED",
+        (1, 0xB8) => "ZONE_BOUNDARY_ERROR",
+        (1, 0xB9) => "ZONE_IS_FULL",
+        (1, 0xBA) => "ZONE_IS_READ_ONLY",
+        (1, 0xBB) => "ZONE_IS_OFFLINE",
+        (1, 0xBC) => "ZONE_INVALID_WRITE",
+        (1, 0xBD) => "TOO_MANY_ACTIVE_ZONES",
+        (1, 0xBE) => "TOO_MANY_OPEN_ZONES",
+        (1, 0xBF) => "INVALID_ZONE_STATE_TRANSITION",
+        (2, 0x80) => "WRITE_FAULT",
+        (2, 0x81) => "UNRECOVERED_READ_ERROR",
+        (2, 0x85) => "COMPARE_FAILURE",
+        (2, 0x86) => "ACCESS_DENIED",
+        (2, 0x87) => "DEALLOCATED_OR_UNWRITTEN_BLOCK",
+        _ => "UNKNOWN",
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.timestamp / 1_000_000_000;
+        let nanos = self.timestamp % 1_000_000_000;
+        write!(f, "[{secs}.{nanos:09}] Q{} ", self.q_id)?;
+
+        match self.entry {
+            TraceEntry::Submission(cmd) => {
+                let (opcode, c_id, ns_id, flags) = (cmd.opcode, cmd.c_id, cmd.ns_id, cmd.flags);
+                let [prp1, prp2] = cmd.d_ptr;
+                let cdws = [cmd.cdw10, cmd.cdw11, cmd.cdw12, cmd.cdw13, cmd.cdw14, cmd.cdw15];
+                let name = if self.q_id == 0 { admin_opcode_name(opcode) } else { io_opcode_name(opcode) };
+                match name {
+                    Some(name) => write!(f, "SQ {name}")?,
+                    None => write!(f, "SQ OPCODE_0x{opcode:02x}")?,
+                }
+                write!(f, " cid=0x{c_id:04x} nsid=0x{ns_id:x}")?;
+                if flags & 0b11 != 0 {
+                    write!(f, " fuse={}", flags & 0b11)?;
+                }
+                match (self.q_id, opcode) {
+                    // Read, Write, Compare and Zone Append carry the LBA range in CDW10-12
+                    (1.., 0x01 | 0x02 | 0x05 | 0x7D) => {
+                        let slba = (cdws[1] as u64) << 32 | cdws[0] as u64;
+                        write!(f, " slba=0x{slba:x} nlb={}", (cdws[2] & 0xFFFF) + 1)?;
+                        let dtype = (cdws[2] >> 20) & 0xF;
+                        if dtype != 0 {
+                            write!(f, " dtype={dtype} dspec=0x{:x}", cdws[3] >> 16)?;
+                        }
+                    }
+                    _ => {
+                        for (i, cdw) in cdws.iter().enumerate() {
+                            if *cdw != 0 {
+                                write!(f, " cdw{}=0x{cdw:x}", i + 10)?;
+                            }
+                        }
+                    }
+                }
+                write!(f, " prp1=0x{prp1:x} prp2=0x{prp2:x}")
+            }
+            TraceEntry::Completion(entry) => {
+                let (c_id, sq_head, sq_id, dw0) = (entry.c_id, entry.sq_head, entry.sq_id, entry.command_specific1);
+                let status = entry.status >> 1;
+                let (sc, sct) = ((status & 0xFF) as u8, ((status >> 8) & 0x7) as u8);
+                write!(f, "CQ cid=0x{c_id:04x} sqid={sq_id} sqhd={sq_head} dw0=0x{dw0:x} {}", status_name(sct, sc))?;
+                if status != 0 {
+                    write!(f, " (sct=0x{sct:x} sc=0x{sc:02x})")?;
+                    // More and Do Not Retry bits
+                    if (status >> 13) & 1 == 1 {
+                        write!(f, " more")?;
+                    }
+                    if (status >> 14) & 1 == 1 {
+                        write!(f, " dnr")?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}